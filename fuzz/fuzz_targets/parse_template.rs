@@ -0,0 +1,9 @@
+#![no_main]
+
+#[allow(dead_code)]
+#[path = "../../src/main.rs"]
+mod tdmcli;
+
+libfuzzer_sys::fuzz_target!(|data: &[u8]| {
+    let _ = tdmcli::parse_template_bytes(data);
+});
@@ -1,14 +1,21 @@
 use std::env;
-use globset::{Glob, GlobSet, GlobSetBuilder};
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
 use std::fs::{self, File};
 use std::io::{Read, Write};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use rayon::prelude::*;
 use indicatif::{ProgressBar, ProgressStyle};
 #[cfg(target_os = "windows")]
 use std::os::windows::fs::MetadataExt;
 
 use base64::{engine::general_purpose, Engine as _};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, rand_core::RngCore};
+use aes_gcm::{Aes256Gcm, Nonce};
 #[cfg(target_os = "windows")]
 fn is_hidden(path: &Path) -> bool {
     if let Ok(metadata) = fs::metadata(path) {
@@ -28,7 +35,56 @@ fn is_hidden(path: &Path) -> bool {
 }
 
 const VERSION: &str = "1.0";
+const FORMAT_VERSION: u32 = 2;
+const INLINE_COMMENTS_DIRECTIVE: &str = "# tdmcli: inline-comments";
+const VCS_DIRS: &[&str] = &[".git", ".svn", ".hg", ".bzr", "CVS"];
 const KEY: &str = "tdmcliKeyy";
+#[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd"))]
+const MIME_TYPE: &str = "application/x-tdmcli";
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+const GZIP_EXTENSION: &str = "gz";
+const FIRST_VOLUME_EXTENSION: &str = "001";
+const STORE_LOCK_FILE_NAME: &str = ".tdmcli-store.lock";
+const BLOBS_DIR_NAME: &str = ".tdmcli-blobs";
+// `create --no-compress` warns when the template is this many times the size of its files,
+// unless it is smaller than SIZE_RATIO_MIN_BYTES (where the per-file metadata dominates anyway).
+const SIZE_RATIO_WARNING: f64 = 1.4;
+const SIZE_RATIO_MIN_BYTES: u64 = 64 * 1024;
+const DEFAULT_LOCK_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_TEMPLATE_EXTENSION: &str = "tdmcli";
+const LOCKFILE_NAME: &str = "tdmcli.lock";
+const PROJECT_FILE_NAME: &str = ".tdmcli.toml";
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+const DECODE_CHUNK_SIZE: usize = 64 * 1024;
+const BINARY_SNIFF_LEN: usize = 8000;
+const STATS_LARGEST_COUNT: usize = 5;
+const GIT_ADD_BATCH: usize = 500;
+const UPDATE_URL: &str = "https://raw.githubusercontent.com/MrTigerST/tdmcli/main/version";
+const RELEASES_URL: &str = "https://github.com/MrTigerST/tdmcli/releases/latest";
+
+fn expand_tilde(path: &Path) -> PathBuf {
+    let mut components = path.components();
+    match (components.next(), dirs::home_dir()) {
+        (Some(Component::Normal(first)), Some(home)) if first == "~" => home.join(components.as_path()),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn normalize_path(path: &Path) -> PathBuf {
+    let path = &expand_tilde(path);
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut normalized = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other.as_os_str()),
+        }
+    }
+    normalized
+}
 
 fn get_config_file_path() -> PathBuf {
     let mut config_dir = dirs::config_dir().unwrap_or_else(|| PathBuf::from("."));
@@ -39,18 +95,63 @@ fn get_config_file_path() -> PathBuf {
 }
 
 
-fn read_config_template_dir() -> Option<PathBuf> {
-    let config_file = get_config_file_path();
-    if config_file.exists() {
-        let contents = fs::read_to_string(config_file).ok()?;
-        for line in contents.lines() {
-            let line = line.trim();
-            if line.starts_with("template_dir") {
-                if let Some(pos) = line.find('=') {
-                    let value = line[pos+1..].trim().trim_matches('"');
-                    if !value.is_empty() {
-                        return Some(PathBuf::from(value));
-                    }
+#[derive(Deserialize, Default)]
+struct Config {
+    template_dir: Option<String>,
+    ignore_case: Option<bool>,
+    template_extension: Option<String>,
+    update_url: Option<String>,
+    lock_timeout: Option<u64>,
+    #[serde(default)]
+    transform: Vec<TransformConfig>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ProjectConfig {
+    name: Option<String>,
+    ignore: Vec<String>,
+    ext: Vec<String>,
+    exclude_ext: Vec<String>,
+    root_markers: Vec<String>,
+    hidden_folders: bool,
+    exclude_ignore: bool,
+    include_vcs: bool,
+    ignore_case: Option<bool>,
+    preserve_owner: bool,
+    preserve_xattrs: bool,
+    preserve_root_name: bool,
+    format_version: Option<u32>,
+}
+
+fn read_toml_file<T: serde::de::DeserializeOwned>(path: &Path) -> Option<T> {
+    let contents = fs::read_to_string(path).ok()?;
+    match toml::from_str(&contents) {
+        Ok(value) => Some(value),
+        Err(err) => {
+            eprintln!("Could not parse {}: {}", path.display(), err.message());
+            std::process::exit(1);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TransformConfig {
+    #[serde(rename = "match")]
+    pattern: String,
+    find: String,
+    replace: String,
+    template: Option<String>,
+}
+
+fn read_legacy_template_dir(contents: &str) -> Option<String> {
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.starts_with("template_dir") {
+            if let Some(pos) = line.find('=') {
+                let value = line[pos+1..].trim().trim_matches('"');
+                if !value.is_empty() {
+                    return Some(value.to_string());
                 }
             }
         }
@@ -58,6 +159,44 @@ fn read_config_template_dir() -> Option<PathBuf> {
     None
 }
 
+fn read_config() -> Config {
+    let config_file = get_config_file_path();
+    let Ok(contents) = fs::read_to_string(&config_file) else {
+        return Config::default();
+    };
+    match toml::from_str(&contents) {
+        Ok(config) => config,
+        Err(err) => {
+            if contents.contains('[') {
+                eprintln!("Warning: could not parse {}: {}", config_file.display(), err.message());
+            }
+            Config {
+                template_dir: read_legacy_template_dir(&contents),
+                ..Config::default()
+            }
+        }
+    }
+}
+
+fn read_config_template_dir() -> Option<PathBuf> {
+    read_config().template_dir
+        .filter(|value| !value.is_empty())
+        .map(|value| {
+            let config_file = get_config_file_path();
+            resolve_config_template_dir(&value, config_file.parent().unwrap_or(Path::new(".")))
+        })
+}
+
+// A relative template_dir is relative to the directory of the config file, not to the current directory.
+fn resolve_config_template_dir(value: &str, config_dir: &Path) -> PathBuf {
+    let path = &expand_tilde(Path::new(value));
+    if path.is_absolute() {
+        normalize_path(path)
+    } else {
+        normalize_path(&config_dir.join(path))
+    }
+}
+
 fn change_template_dir(new_dir: &Path) {
     if !new_dir.exists() {
         fs::create_dir_all(new_dir).unwrap_or_else(|_| {
@@ -66,46 +205,259 @@ fn change_template_dir(new_dir: &Path) {
         });
     }
     let config_file = get_config_file_path();
-    let config_contents = format!("template_dir = \"{}\"\n", new_dir.to_string_lossy());
+    let existing = fs::read_to_string(&config_file).unwrap_or_default();
+    let mut config_contents = format!("template_dir = {}\n",
+        toml::Value::String(new_dir.to_string_lossy().to_string()));
+    for line in existing.lines().filter(|line| !line.trim().starts_with("template_dir")) {
+        config_contents.push_str(line);
+        config_contents.push('\n');
+    }
     fs::write(&config_file, config_contents).unwrap();
     println!("Template directory changed to {:?}", new_dir);
 }
 
-fn load_ignore_patterns(root_dir: &Path) -> GlobSet {
-    let mut builder = GlobSetBuilder::new();
-    let ignore_file = root_dir.join(".tdmignore");
+fn reset_template_dir() {
+    let config_file = get_config_file_path();
+    let existing = fs::read_to_string(&config_file).unwrap_or_default();
+    if existing.lines().any(|line| line.trim().starts_with("template_dir")) {
+        let config_contents: String = existing.lines()
+            .filter(|line| !line.trim().starts_with("template_dir"))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        fs::write(&config_file, config_contents).unwrap();
+    }
+    println!("Template directory reset to the default location {:?}", resolve_templates_dir());
+}
+
+struct Transform {
+    matcher: globset::GlobMatcher,
+    find: Regex,
+    replace: String,
+}
 
-    if let Ok(contents) = fs::read_to_string(&ignore_file) {
-        for line in contents.lines() {
-            let trimmed = line.trim();
-            if trimmed.is_empty() || trimmed.starts_with('#') {
+fn load_transforms(template_name: &str) -> Vec<Transform> {
+    let mut transforms = Vec::new();
+    for rule in read_config().transform {
+        if rule.template.as_ref().is_some_and(|name| name != template_name) {
+            continue;
+        }
+        let matcher = match Glob::new(&rule.pattern) {
+            Ok(glob) => glob.compile_matcher(),
+            Err(err) => {
+                eprintln!("Warning: skipping transform with invalid match '{}': {}", rule.pattern, err);
+                continue;
+            }
+        };
+        let find = match Regex::new(&rule.find) {
+            Ok(find) => find,
+            Err(err) => {
+                eprintln!("Warning: skipping transform with invalid find '{}': {}", rule.find, err);
                 continue;
             }
-            let mut pattern = trimmed.to_string();
+        };
+        transforms.push(Transform { matcher, find, replace: rule.replace });
+    }
+    transforms
+}
+
+fn is_binary(content: &[u8]) -> bool {
+    content.iter().take(BINARY_SNIFF_LEN).any(|&b| b == 0)
+}
+
+fn file_is_binary(path: &Path) -> bool {
+    let mut head = Vec::new();
+    File::open(path)
+        .and_then(|file| file.take(BINARY_SNIFF_LEN as u64).read_to_end(&mut head))
+        .is_ok_and(|_| is_binary(&head))
+}
+
+fn apply_transforms(path: &str, content: Vec<u8>, text: Option<bool>, transforms: &[Transform]) -> Vec<u8> {
+    if !text.unwrap_or_else(|| !is_binary(&content)) {
+        return content;
+    }
+    let mut text = match String::from_utf8(content) {
+        Ok(text) => text,
+        Err(err) => return err.into_bytes(),
+    };
+    for transform in transforms.iter().filter(|transform| transform.matcher.is_match(path)) {
+        text = transform.find.replace_all(&text, transform.replace.as_str()).into_owned();
+    }
+    text.into_bytes()
+}
+
+struct IgnorePatterns {
+    set: GlobSet,
+    rule_of_glob: Vec<Option<usize>>,
+    rules: Vec<(usize, String)>,
+    hits: Vec<AtomicUsize>,
+}
 
-            if pattern.starts_with('/') {
-                pattern.remove(0);
+fn parse_ignore_line(line: &str, inline_comments: bool) -> Option<String> {
+    let mut trimmed = line.trim();
+    if trimmed.is_empty() || trimmed.starts_with('#') {
+        return None;
+    }
+
+    if inline_comments {
+        let mut previous = None;
+        for (index, c) in trimmed.char_indices() {
+            if c == '#' && previous.is_some_and(char::is_whitespace) {
+                trimmed = trimmed[..index].trim_end();
+                break;
             }
+            previous = Some(c);
+        }
+    }
 
-            if pattern.ends_with('/') {
-                let dir_pattern = pattern.trim_end_matches('/').to_string();
-                builder.add(Glob::new(&dir_pattern).unwrap());
-                let wildcard_pattern = format!("{}**", pattern);
-                builder.add(Glob::new(&wildcard_pattern).unwrap());
-            } else if pattern.contains('/') {
+    let pattern = match trimmed.strip_prefix('\\') {
+        Some(rest) if rest.starts_with('#') || rest.starts_with('!') => rest,
+        _ => trimmed,
+    };
+    Some(pattern.to_string())
+}
 
-                builder.add(Glob::new(&pattern).unwrap());
-                let wildcard_pattern = format!("{}/**", pattern);
-                builder.add(Glob::new(&wildcard_pattern).unwrap());
-            } else {
-                builder.add(Glob::new(&pattern).unwrap());
+fn build_glob(pattern: &str, case_insensitive: bool) -> Glob {
+    GlobBuilder::new(pattern).case_insensitive(case_insensitive).build().unwrap()
+}
+
+struct TextAttributes {
+    set: GlobSet,
+    rule_of_glob: Vec<Option<usize>>,
+    values: Vec<Option<bool>>,
+}
+
+impl TextAttributes {
+    fn classify(&self, path: &Path) -> Option<bool> {
+        self.set.matches(path).into_iter()
+            .filter_map(|index| self.rule_of_glob[index])
+            .max()
+            .and_then(|rule| self.values[rule])
+    }
+}
+
+fn text_attribute(attribute: &str) -> Option<Option<bool>> {
+    match attribute {
+        "text" => Some(Some(true)),
+        "-text" | "binary" => Some(Some(false)),
+        "!text" | "text=auto" => Some(None),
+        _ => None,
+    }
+}
+
+fn lfs_attribute(attribute: &str) -> Option<Option<bool>> {
+    match attribute {
+        "filter=lfs" => Some(Some(true)),
+        "-filter" => Some(Some(false)),
+        "!filter" => Some(None),
+        _ => attribute.starts_with("filter=").then_some(Some(false)),
+    }
+}
+
+const LFS_POINTER_PREFIX: &[u8] = b"version https://git-lfs.github.com/spec/";
+
+fn is_lfs_pointer(path: &Path) -> bool {
+    let mut head = Vec::new();
+    File::open(path)
+        .and_then(|file| file.take(LFS_POINTER_PREFIX.len() as u64).read_to_end(&mut head))
+        .is_ok_and(|_| head == LFS_POINTER_PREFIX)
+}
+
+fn load_text_attributes(root_dir: &Path) -> TextAttributes {
+    load_attributes(root_dir, text_attribute)
+}
+
+fn load_attributes(root_dir: &Path, attribute: fn(&str) -> Option<Option<bool>>) -> TextAttributes {
+    let mut builder = GlobSetBuilder::new();
+    let mut rule_of_glob = Vec::new();
+    let mut values = Vec::new();
+    let contents = fs::read_to_string(root_dir.join(".gitattributes")).unwrap_or_default();
+    for line in contents.lines() {
+        let mut fields = line.split_whitespace();
+        let Some(pattern) = fields.next().filter(|pattern| !pattern.starts_with('#') && !pattern.starts_with("[attr]")) else {
+            continue;
+        };
+        let Some(value) = fields.filter_map(attribute).next_back() else {
+            continue;
+        };
+        if let Err(err) = Glob::new(pattern) {
+            eprintln!("Warning: skipping .gitattributes pattern '{}': {}", pattern, err);
+            continue;
+        }
+        add_ignore_pattern(&mut builder, &mut rule_of_glob, pattern.to_string(), Some(values.len()), false);
+        values.push(value);
+    }
+    TextAttributes { set: builder.build().unwrap(), rule_of_glob, values }
+}
+
+fn load_ignore_patterns(root_dir: &Path, extra_patterns: &[&str], case_insensitive: bool) -> IgnorePatterns {
+    load_ignore_file(&root_dir.join(".tdmignore"), extra_patterns, case_insensitive)
+}
+
+fn add_ignore_pattern(builder: &mut GlobSetBuilder, rule_of_glob: &mut Vec<Option<usize>>, mut pattern: String, rule: Option<usize>, case_insensitive: bool) {
+    if pattern.starts_with('/') {
+        pattern.remove(0);
+    }
+
+    if pattern.ends_with('/') {
+        let dir_pattern = pattern.trim_end_matches('/').to_string();
+        builder.add(build_glob(&dir_pattern, case_insensitive));
+        let wildcard_pattern = format!("{}**", pattern);
+        builder.add(build_glob(&wildcard_pattern, case_insensitive));
+        rule_of_glob.extend([rule, rule]);
+    } else if pattern.contains('/') {
+        builder.add(build_glob(&pattern, case_insensitive));
+        let wildcard_pattern = format!("{}/**", pattern);
+        builder.add(build_glob(&wildcard_pattern, case_insensitive));
+        rule_of_glob.extend([rule, rule]);
+    } else {
+        builder.add(build_glob(&pattern, case_insensitive));
+        rule_of_glob.push(rule);
+    }
+}
+
+fn load_ignore_file(ignore_file: &Path, extra_patterns: &[&str], case_insensitive: bool) -> IgnorePatterns {
+    let mut builder = GlobSetBuilder::new();
+    let mut rule_of_glob = Vec::new();
+    let mut rules = Vec::new();
+    for pattern in extra_patterns {
+        add_ignore_pattern(&mut builder, &mut rule_of_glob, pattern.to_string(), None, case_insensitive);
+    }
+    if let Ok(contents) = fs::read_to_string(ignore_file) {
+        let inline_comments = contents.lines().any(|line| line.trim() == INLINE_COMMENTS_DIRECTIVE);
+        for (line_number, line) in contents.lines().enumerate() {
+            let Some(pattern) = parse_ignore_line(line, inline_comments) else {
+                continue;
+            };
+            let rule = Some(rules.len());
+            rules.push((line_number + 1, line.trim().to_string()));
+            add_ignore_pattern(&mut builder, &mut rule_of_glob, pattern, rule, case_insensitive);
+        }
+    }
+    IgnorePatterns {
+        set: builder.build().unwrap(),
+        rule_of_glob,
+        hits: rules.iter().map(|_| AtomicUsize::new(0)).collect(),
+        rules,
+    }
+}
+
+fn record_ignore_match(patterns: &IgnorePatterns, candidate: &str) -> bool {
+    let matches = patterns.set.matches(candidate);
+    for index in &matches {
+        if let Some(rule) = patterns.rule_of_glob[*index] {
+            patterns.hits[rule].fetch_add(1, Ordering::Relaxed);
+            if let Some((line, pattern)) = patterns.rules.get(rule) {
+                log::debug!("'{}' ignored by rule '{}' (line {})", candidate, pattern, line);
             }
         }
     }
-    builder.build().unwrap()
+    if !matches.is_empty() {
+        log::trace!("'{}' matched {} ignore globs", candidate, matches.len());
+    }
+    !matches.is_empty()
 }
 
-fn should_ignore(path: &Path, root_dir: &Path, patterns: &GlobSet, exclude_tdmignore: bool) -> bool {
+fn should_ignore(path: &Path, root_dir: &Path, patterns: &IgnorePatterns, exclude_tdmignore: bool) -> bool {
     let relative_path = path.strip_prefix(root_dir).unwrap_or(path);
 
     if relative_path == Path::new(".tdmignore") {
@@ -113,13 +465,13 @@ fn should_ignore(path: &Path, root_dir: &Path, patterns: &GlobSet, exclude_tdmig
     }
 
     let relative_str = relative_path.to_str().unwrap_or("");
-    if patterns.is_match(relative_str) {
+    if record_ignore_match(patterns, relative_str) {
         return true;
     }
 
     for component in relative_path.components() {
         if let Some(comp_str) = component.as_os_str().to_str() {
-            if patterns.is_match(comp_str) {
+            if record_ignore_match(patterns, comp_str) {
                 return true;
             }
         }
@@ -127,16 +479,126 @@ fn should_ignore(path: &Path, root_dir: &Path, patterns: &GlobSet, exclude_tdmig
     false
 }
 
+fn validate_ignore(root_dir: &Path, ignore_file: Option<&Path>, include_hidden: bool, exclude_vcs: bool, ignore_case: bool) {
+    let extra_patterns: &[&str] = if exclude_vcs { VCS_DIRS } else { &[] };
+    let patterns = match ignore_file {
+        Some(ignore_file) if !ignore_file.is_file() => {
+            println!("Ignore file '{}' not found.", ignore_file.display());
+            std::process::exit(1);
+        }
+        Some(ignore_file) => load_ignore_file(ignore_file, extra_patterns, ignore_case),
+        None => load_ignore_patterns(root_dir, extra_patterns, ignore_case),
+    };
 
-fn get_templates_dir() -> PathBuf {
+    let mut included = Vec::new();
+    let mut ignored = Vec::new();
+    let mut walker = walkdir::WalkDir::new(root_dir).min_depth(1).sort_by_file_name().into_iter();
+    while let Some(entry) = walker.next() {
+        let Ok(entry) = entry else {
+            continue;
+        };
+        let path = entry.path();
+        let relative_path = path.strip_prefix(root_dir).unwrap_or(path).to_string_lossy().to_string();
+        let is_dir = entry.file_type().is_dir();
+        if is_dir && !include_hidden && is_hidden(path) {
+            ignored.push(format!("{}/ (hidden)", relative_path));
+            walker.skip_current_dir();
+        } else if should_ignore(path, root_dir, &patterns, false) {
+            if is_dir {
+                ignored.push(format!("{}/", relative_path));
+                walker.skip_current_dir();
+            } else {
+                ignored.push(relative_path);
+            }
+        } else if !is_dir {
+            included.push(relative_path);
+        }
+    }
+
+    println!("Included ({}):", included.len());
+    for path in &included {
+        println!("  {}", path);
+    }
+    println!("Ignored ({}):", ignored.len());
+    for path in &ignored {
+        println!("  {}", path);
+    }
+    report_unmatched_ignore_patterns(&patterns);
+}
+
+fn report_unmatched_ignore_patterns(patterns: &IgnorePatterns) {
+    for ((line_number, rule), hits) in patterns.rules.iter().zip(&patterns.hits) {
+        if hits.load(Ordering::Relaxed) == 0 {
+            eprintln!("Warning: .tdmignore pattern '{}' (line {}) did not match any path.", rule, line_number);
+        }
+    }
+}
+
+fn resolve_templates_dir() -> PathBuf {
     if let Some(dir) = read_config_template_dir() {
-        fs::create_dir_all(&dir).unwrap_or_default();
         return dir;
     }
-    
+
     let mut path = dirs::data_local_dir().unwrap_or_else(|| PathBuf::from("."));
     path.push("tdmcli/templates");
-    fs::create_dir_all(&path).unwrap_or_default();
+    path
+}
+
+fn templates_dir_error(dir: &Path, err: &std::io::Error) -> ! {
+    eprintln!("Cannot use the templates directory '{}': {}.", dir.display(), err);
+    eprintln!("Check that it exists and that you have permission to read and write it, or pick another location with `tdmcli change-dir <new_directory>`.");
+    std::process::exit(1);
+}
+
+// Takes an advisory lock on the templates directory, exclusive for commands that change templates
+// and shared for commands that read several of them. The lock is released when the file is dropped.
+fn lock_store(templates_dir: &Path, exclusive: bool) -> Result<Option<File>, String> {
+    use fs2::FileExt;
+    let path = templates_dir.join(STORE_LOCK_FILE_NAME);
+    let file = match fs::OpenOptions::new().create(true).truncate(false).write(true).open(&path) {
+        Ok(file) => file,
+        Err(err) => {
+            log::debug!("not locking {}: {}", path.display(), err);
+            return Ok(None);
+        }
+    };
+    let timeout = read_config().lock_timeout.unwrap_or(DEFAULT_LOCK_TIMEOUT_SECS);
+    let started = Instant::now();
+    let mut waiting = false;
+    loop {
+        let locked = if exclusive { file.try_lock_exclusive() } else { FileExt::try_lock_shared(&file) };
+        match locked {
+            Ok(()) => return Ok(Some(file)),
+            Err(err) if err.kind() == fs2::lock_contended_error().kind() => {}
+            Err(err) => {
+                log::debug!("not locking {}: {}", path.display(), err);
+                return Ok(None);
+            }
+        }
+        if started.elapsed() >= Duration::from_secs(timeout) {
+            return Err(format!("another tdmcli is running on the templates directory '{}' (gave up after {} seconds)",
+                templates_dir.display(), timeout));
+        }
+        if !waiting {
+            eprintln!("Another tdmcli is using the templates directory, waiting up to {} seconds...", timeout);
+            waiting = true;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn lock_store_or_exit(templates_dir: &Path, exclusive: bool) -> Option<File> {
+    lock_store(templates_dir, exclusive).unwrap_or_else(|err| {
+        eprintln!("Cannot lock the templates directory: {}.", err);
+        std::process::exit(1);
+    })
+}
+
+fn get_templates_dir() -> PathBuf {
+    let path = resolve_templates_dir();
+    if let Err(err) = fs::create_dir_all(&path).and_then(|_| fs::read_dir(&path).map(|_| ())) {
+        templates_dir_error(&path, &err);
+    }
     path
 }
 
@@ -148,339 +610,5391 @@ fn xor_crypt(data: &[u8], key: &str) -> Vec<u8> {
         .collect()
 }
 
-fn process_file(file_path: &Path, root_dir: &Path) -> (String, Vec<u8>) {
-    let relative_path = file_path.strip_prefix(root_dir)
-        .unwrap()
-        .to_string_lossy()
-        .to_string();
-    let mut content = Vec::new();
-    File::open(file_path).unwrap().read_to_end(&mut content).unwrap();
-    let encrypted_content = xor_crypt(&content, KEY);
-    (relative_path, encrypted_content)
+fn xor_crypt_at(data: &mut [u8], key: &str, offset: usize) {
+    let key_bytes = key.as_bytes();
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= key_bytes[(offset + i) % key_bytes.len()];
+    }
 }
 
-fn create_template(template_name: &str, root_dir: &Path, include_hidden: bool, exclude_ignore: bool) {
-    println!("Loading... Creating template '{}'.", template_name);
-    let template_path = get_templates_dir().join(format!("{}.tdmcli", template_name));
-    let ignore_patterns = load_ignore_patterns(root_dir);
-
-    let walker = walkdir::WalkDir::new(root_dir).into_iter().filter_entry(|entry| {
-        let path = entry.path();
-    
-        if !include_hidden && entry.file_type().is_dir() {
-            return !is_hidden(path);
-        }
-        true
-    });    
-
-    let file_entries: Vec<PathBuf> = walker
-        .filter_map(|entry| entry.ok())
-        .filter(|entry| {
-            let path = entry.path();
+// With `create --byte-paths`, entry paths are stored percent-encoded (header `PATHS: percent`): control characters,
+// '%' and every non-ASCII byte become %XX, so non-UTF-8 file names survive. Only those bytes may be escaped, so a
+// path can't smuggle a '/' or '.' past the safe path check.
+const BYTE_PATHS: &str = "percent";
 
-            if let Some(file_name) = path.file_name().and_then(|s| s.to_str()) {
-                if file_name == ".tdmignore" {
-                    return !exclude_ignore;
-                }
-            }
+fn must_escape_path_byte(byte: u8) -> bool {
+    byte < 0x20 || byte == 0x7f || byte == b'%' || byte >= 0x80
+}
 
-            if should_ignore(path, root_dir, &ignore_patterns, exclude_ignore) {
-                return false;
-            }
+fn encode_path_bytes(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        if must_escape_path_byte(byte) {
+            encoded.push_str(&format!("%{:02X}", byte));
+        } else {
+            encoded.push(byte as char);
+        }
+    }
+    encoded
+}
 
-            entry.file_type().is_file()
-        })
-        .map(|entry| entry.path().to_path_buf())
-        .collect();
+fn decode_path_bytes(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let byte = tail.get(..2)
+                .and_then(|digits| std::str::from_utf8(digits).ok())
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .filter(|&byte| byte != 0 && must_escape_path_byte(byte))?;
+            bytes.push(byte);
+            rest = &tail[2..];
+        } else if must_escape_path_byte(byte) {
+            return None;
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    Some(bytes)
+}
 
+#[cfg(unix)]
+fn os_path_bytes(path: &Path) -> std::borrow::Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    std::borrow::Cow::Borrowed(path.as_os_str().as_bytes())
+}
 
-    let pb_files = ProgressBar::new(file_entries.len() as u64);
-    pb_files.set_style(ProgressStyle::default_bar()
-        .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} files")
-        .unwrap());
+#[cfg(not(unix))]
+fn os_path_bytes(path: &Path) -> std::borrow::Cow<'_, [u8]> {
+    std::borrow::Cow::Owned(path.to_string_lossy().into_owned().into_bytes())
+}
 
-    let file_results: Vec<(String, Vec<u8>)> = file_entries.par_iter()
-        .map(|file| {
-            let res = process_file(file, root_dir);
-            pb_files.inc(1);
-            res
-        })
-        .collect();
-    pb_files.finish_with_message("File processing complete");
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
 
-    let empty_dirs: Vec<PathBuf> = walkdir::WalkDir::new(root_dir)
-        .into_iter()
-        .filter_map(|entry| entry.ok())
-        .filter(|entry| {
-            let path = entry.path();
-            if !path.is_dir() {
-                return false;
-            }
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
 
-            if !include_hidden {
-                if let Some(name) = path.file_name().and_then(|s| s.to_str()) {
-                    if name.starts_with('.') {
-                        return false;
-                    }
-                }
-            }
+fn path_text(path: &Path, byte_paths: bool) -> String {
+    if byte_paths {
+        encode_path_bytes(&os_path_bytes(path))
+    } else {
+        path.to_string_lossy().to_string()
+    }
+}
 
-            if should_ignore(path, root_dir, &ignore_patterns, exclude_ignore) {
-                return false;
-            }
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
 
-            fs::read_dir(path)
-                .map(|iter| {
-                    iter.filter(|entry| {
-                        if let Ok(entry) = entry {
-                            if !include_hidden {
-                                if let Some(name) = entry.file_name().to_str() {
-                                    return !name.starts_with('.');
-                                }
-                            }
-                            true
-                        } else {
-                            false
-                        }
-                    }).next().is_none()
-                })
-                .unwrap_or(false)
-        })
-        .map(|entry| entry.path().to_path_buf())
-        .collect();
+fn sha256_hex(data: &[u8]) -> String {
+    to_hex(&Sha256::digest(data))
+}
 
-    let mut template_file = File::create(&template_path).unwrap();
+#[derive(Clone, Copy, PartialEq, Default)]
+enum Encoding {
+    #[default]
+    Base64,
+    Base64Url,
+    Hex,
+}
 
-    for dir in empty_dirs {
-        let relative_path = dir.strip_prefix(root_dir)
-            .unwrap()
-            .to_string_lossy()
-            .to_string();
-        if relative_path.is_empty() {
-            continue;
+impl Encoding {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "base64" => Some(Encoding::Base64),
+            "base64url" => Some(Encoding::Base64Url),
+            "hex" => Some(Encoding::Hex),
+            _ => None,
         }
-        writeln!(template_file, "DIR: {}", relative_path).unwrap();
-        writeln!(template_file, "END_OF_DIR").unwrap();
     }
 
-    for (relative_path, encrypted_content) in file_results {
-        let encoded = general_purpose::STANDARD.encode(&encrypted_content);
-        writeln!(template_file, "FILE: {}", relative_path).unwrap();
-        writeln!(template_file, "SIZE: {}", encoded.len()).unwrap();
-        writeln!(template_file, "{}", encoded).unwrap();
-        writeln!(template_file, "END_OF_FILE").unwrap();
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Base64 => "base64",
+            Encoding::Base64Url => "base64url",
+            Encoding::Hex => "hex",
+        }
     }
 
-    println!("Template '{}' created successfully.", template_name);
-}
-
-fn apply_template(template_name: &str) {
-    println!("Loading... Applying template '{}'.", template_name);
-    let template_path = get_templates_dir().join(format!("{}.tdmcli", template_name));
-    if !template_path.exists() {
-        println!("Template '{}' not found.", template_name);
-        return;
+    fn encode(self, data: &[u8]) -> String {
+        match self {
+            Encoding::Base64 => general_purpose::STANDARD.encode(data),
+            Encoding::Base64Url => general_purpose::URL_SAFE_NO_PAD.encode(data),
+            Encoding::Hex => to_hex(data),
+        }
     }
 
-    let content = fs::read_to_string(&template_path).unwrap();
-    let mut file_entries = Vec::new();
-    let mut dir_entries = Vec::new();
-    let mut lines = content.lines();
+    // A cheap check of the alphabet and length of an encoded body, so that damaged content is reported when the
+    // template is read rather than halfway through writing its files.
+    fn is_valid(self, body: &str) -> bool {
+        match self {
+            Encoding::Base64 => {
+                let digits = body.trim_end_matches('=');
+                body.len().is_multiple_of(4) && body.len() - digits.len() <= 2
+                    && digits.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/')
+            }
+            Encoding::Base64Url => body.len() % 4 != 1 && body.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'),
+            Encoding::Hex => body.len().is_multiple_of(2) && body.bytes().all(|b| b.is_ascii_hexdigit()),
+        }
+    }
 
-    while let Some(line) = lines.next() {
-        if line.starts_with("FILE: ") {
-            let file_name = line[6..].to_string();
-            let size_line = lines.next().unwrap();
-            let size: usize = size_line[6..].parse().unwrap();
-            let encoded = lines.next().unwrap().to_string();
-            let _ = lines.next().unwrap();
-            file_entries.push((file_name, size, encoded));
-        } else if line.starts_with("DIR: ") {
-            let dir_name = line[5..].to_string();
-            let end_line = lines.next().unwrap();
-            if end_line != "END_OF_DIR" {
-                eprintln!("Template format error: expected END_OF_DIR after directory entry.");
+    fn decoded_len(self, body: &str) -> u64 {
+        match self {
+            Encoding::Base64 => {
+                let padding = body.bytes().rev().take_while(|&b| b == b'=').count();
+                (body.len() / 4 * 3).saturating_sub(padding) as u64
             }
-            dir_entries.push(dir_name);
+            Encoding::Base64Url => (body.len() * 3 / 4) as u64,
+            Encoding::Hex => (body.len() / 2) as u64,
         }
     }
 
-    for dir_name in dir_entries {
-        let path = Path::new(&dir_name);
-        fs::create_dir_all(path).unwrap();
+    fn reader<'a>(self, body: &'a str) -> Box<dyn Read + 'a> {
+        match self {
+            Encoding::Base64 => Box::new(base64::read::DecoderReader::new(body.as_bytes(), &general_purpose::STANDARD)),
+            Encoding::Base64Url => Box::new(base64::read::DecoderReader::new(body.as_bytes(), &general_purpose::URL_SAFE_NO_PAD)),
+            Encoding::Hex => Box::new(HexReader { digits: body.as_bytes() }),
+        }
     }
+}
 
-    let pb = ProgressBar::new(file_entries.len() as u64);
-    pb.set_style(ProgressStyle::default_bar()
-        .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} files")
-        .unwrap());
+struct HexReader<'a> {
+    digits: &'a [u8],
+}
 
-    file_entries.par_iter().for_each(|(file_name, size, encoded)| {
-        let encrypted_content = general_purpose::STANDARD.decode(encoded).unwrap();
-        if encoded.len() != *size {
-            eprintln!("Warning: the declared size does not match the encoded content for file {}", file_name);
+impl Read for HexReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = buf.len().min(self.digits.len() / 2);
+        if count == 0 && !self.digits.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "odd number of hex digits"));
         }
-        let decrypted_content = xor_crypt(&encrypted_content, KEY);
-        let path = Path::new(file_name);
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent).unwrap();
+        for (byte, pair) in buf.iter_mut().zip(self.digits.chunks_exact(2)).take(count) {
+            let pair = std::str::from_utf8(pair).ok()
+                .filter(|pair| pair.bytes().all(|digit| digit.is_ascii_hexdigit()))
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok());
+            *byte = pair.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid hex digit"))?;
         }
-        File::create(path).unwrap().write_all(&decrypted_content).unwrap();
-        pb.inc(1);
-    });
-    pb.finish_with_message("Template applied successfully");
+        self.digits = &self.digits[count * 2..];
+        Ok(count)
+    }
 }
 
-fn delete_template(template_name: &str) {
-    let template_path = get_templates_dir().join(format!("{}.tdmcli", template_name));
-    if fs::remove_file(&template_path).is_ok() {
-        println!("Template '{}' deleted successfully.", template_name);
-    } else {
+fn from_hex(digits: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    HexReader { digits: digits.trim().as_bytes() }.read_to_end(&mut bytes).ok()?;
+    Some(bytes)
+}
+
+// Undoes the XOR obfuscation of a stream of body bytes, `offset` bytes into the content.
+struct XorReader<R> {
+    inner: R,
+    offset: usize,
+}
+
+impl<R: Read> Read for XorReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        xor_crypt_at(&mut buf[..read], KEY, self.offset);
+        self.offset += read;
+        Ok(read)
+    }
+}
+
+// How the bodies of a template turn back into file contents: for encrypted templates, `cipher` is the key that
+// unlock_template derived from the passphrase.
+#[derive(Clone, Copy)]
+struct BodyCodec<'a> {
+    encoding: Encoding,
+    compressed: bool,
+    cipher: Option<&'a TemplateCipher>,
+}
+
+// Streams the content of `file` into `writer` and returns its SHA-256. Compressed bodies are gunzipped on the
+// fly, so only DECODE_CHUNK_SIZE bytes of the content are in memory at a time, and a body that expands past the
+// LENGTH of its file is rejected instead of being written out. Encrypted files are decrypted here, one at a time,
+// since AES-GCM only authenticates a whole ciphertext.
+fn decode_body(file: &TemplateFile, codec: BodyCodec, writer: &mut impl Write) -> std::io::Result<String> {
+    let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string());
+    let decrypted;
+    let raw: Box<dyn Read + '_> = match (&file.nonce, codec.cipher) {
+        (Some(nonce), Some(cipher)) => {
+            let mut ciphertext = Vec::new();
+            codec.encoding.reader(&file.body).read_to_end(&mut ciphertext)?;
+            decrypted = cipher.decrypt(nonce, &ciphertext)
+                .ok_or_else(|| invalid("failed authentication, the template was modified or is damaged"))?;
+            Box::new(decrypted.as_slice())
+        }
+        (Some(_), None) => return Err(invalid("the content is encrypted")),
+        (None, _) => Box::new(XorReader { inner: codec.encoding.reader(&file.body), offset: 0 }),
+    };
+    // Blob contents are stored as they are, whatever the header of the template says.
+    let mut decoder: Box<dyn Read + '_> = match codec.compressed && file.blob.is_none() {
+        true => Box::new(flate2::read::GzDecoder::new(raw)),
+        false => raw,
+    };
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; DECODE_CHUNK_SIZE];
+    let mut written = 0;
+    loop {
+        let read = decoder.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        written += read as u64;
+        if file.length.is_some_and(|length| written > length) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "the content is longer than its LENGTH"));
+        }
+        let chunk = &buffer[..read];
+        hasher.update(chunk);
+        writer.write_all(chunk)?;
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+// Templates with the header `COMPRESSION: gzip` (the default of `create`, unless --no-compress) hold the gzip
+// stream of each file's content instead of the content itself, and a LENGTH line with the size of the content.
+// Bodies stay compressed in memory and are only decompressed by decode_body, while they are written out.
+// Encrypted templates compress before encrypting.
+const COMPRESSION: &str = "gzip";
+
+fn gzip(content: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(content)?;
+    encoder.finish()
+}
+
+// `create --encrypt` templates (header `ENCRYPTION: aes-256-gcm`) store the AES-256-GCM ciphertext of each file
+// instead of its XOR-obfuscated bytes, with the random nonce of the file in a NONCE line and no HASH. The key is
+// derived from a passphrase with Argon2id, using the KDF parameters and SALT of the header; KEY_CHECK is a short
+// ciphertext of its own that tells a wrong passphrase apart from a modified file. Paths and metadata stay readable.
+const ENCRYPTION: &str = "aes-256-gcm";
+const KDF_MEMORY_KIB: u32 = 19456;
+const KDF_ITERATIONS: u32 = 2;
+const KDF_LANES: u32 = 1;
+const KEY_CHECK_PLAINTEXT: &[u8] = b"tdmcli";
+const AES_GCM_TAG_LEN: u64 = 16;
+
+struct TemplateCipher {
+    salt: Vec<u8>,
+    params: argon2::Params,
+    cipher: Aes256Gcm,
+}
+
+impl TemplateCipher {
+    fn new(passphrase: &str) -> Result<Self, String> {
+        let mut salt = vec![0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let params = argon2::Params::new(KDF_MEMORY_KIB, KDF_ITERATIONS, KDF_LANES, Some(32)).unwrap();
+        Self::derive(passphrase, salt, params)
+    }
+
+    fn derive(passphrase: &str, salt: Vec<u8>, params: argon2::Params) -> Result<Self, String> {
+        let mut key = [0u8; 32];
+        argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params.clone())
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|err| format!("could not derive the key ({})", err))?;
+        Ok(TemplateCipher { salt, params, cipher: Aes256Gcm::new(&key.into()) })
+    }
+
+    fn encrypt(&self, content: &[u8]) -> (String, Vec<u8>) {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher.encrypt(&nonce, content).expect("AES-GCM encryption of a buffer cannot fail");
+        (to_hex(&nonce), ciphertext)
+    }
+
+    fn decrypt(&self, nonce: &str, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let nonce = from_hex(nonce).filter(|nonce| nonce.len() == 12)?;
+        self.cipher.decrypt(Nonce::from_slice(&nonce), ciphertext).ok()
+    }
+
+    fn header(&self) -> Vec<(String, String)> {
+        let (nonce, check) = self.encrypt(KEY_CHECK_PLAINTEXT);
+        vec![
+            ("ENCRYPTION".to_string(), ENCRYPTION.to_string()),
+            ("KDF".to_string(), format!("argon2id m={} t={} p={}", self.params.m_cost(), self.params.t_cost(), self.params.p_cost())),
+            ("SALT".to_string(), to_hex(&self.salt)),
+            ("KEY_CHECK".to_string(), format!("{} {}", nonce, to_hex(&check))),
+        ]
+    }
+}
+
+fn parse_kdf(value: &str) -> Option<argon2::Params> {
+    let mut parts = value.split_whitespace();
+    if parts.next() != Some("argon2id") {
+        return None;
+    }
+    let mut cost = |name: &str| parts.next()?.strip_prefix(name)?.parse::<u32>().ok();
+    let (memory, iterations, lanes) = (cost("m=")?, cost("t=")?, cost("p=")?);
+    argon2::Params::new(memory, iterations, lanes, Some(32)).ok()
+}
+
+fn read_passphrase(prompt: &str, confirm: bool) -> Result<String, String> {
+    let mut input = dialoguer::Password::new().with_prompt(prompt);
+    if confirm {
+        input = input.with_confirmation("Repeat the passphrase", "The passphrases don't match.");
+    }
+    input.interact().map_err(|err| format!("cannot read the passphrase ({}), give it with --password", err))
+}
+
+// `create --store-password` keeps the passphrase of an encrypted template in the system keyring (the macOS Keychain,
+// the Windows Credential Manager or the Secret Service), under the service "tdmcli" and the name of the template.
+// A keyring that is missing or locked only means the passphrase is asked for as before.
+const KEYRING_SERVICE: &str = "tdmcli";
+
+fn keyring_passphrase(template_name: &str) -> Option<String> {
+    match keyring::Entry::new(KEYRING_SERVICE, template_name).and_then(|entry| entry.get_password()) {
+        Ok(passphrase) => Some(passphrase),
+        Err(keyring::Error::NoEntry) => None,
+        Err(err) => {
+            log::debug!("cannot read the passphrase of '{}' from the system keyring: {}", template_name, err);
+            None
+        }
+    }
+}
+
+fn store_keyring_passphrase(template_name: &str, passphrase: &str) -> keyring::Result<()> {
+    keyring::Entry::new(KEYRING_SERVICE, template_name)?.set_password(passphrase)
+}
+
+// Derives the key of a `create --encrypt` template from its passphrase and checks it against KEY_CHECK, without
+// decrypting any file: decode_body decrypts each one when it is written or checked, in the parallel workers.
+// The passphrase is `password`, else the one stored in the system keyring (unless `keyring` is false), else it is
+// asked for. Unencrypted templates give no key, without asking for a passphrase.
+fn unlock_template(template_name: &str, template: &Template, password: Option<&str>, keyring: bool) -> Result<Option<TemplateCipher>, String> {
+    if !template.encrypted() {
+        return Ok(None);
+    }
+    let salt = template.header_value("SALT").and_then(from_hex).filter(|salt| salt.len() >= 8)
+        .ok_or("the template has no valid SALT")?;
+    let params = template.header_value("KDF").and_then(parse_kdf)
+        .ok_or("the template has no supported KDF")?;
+    let unlock = |passphrase: &str| {
+        let cipher = TemplateCipher::derive(passphrase, salt.clone(), params.clone())?;
+        let key_check = template.header_value("KEY_CHECK")
+            .and_then(|value| value.split_once(' '))
+            .and_then(|(nonce, check)| cipher.decrypt(nonce, &from_hex(check)?));
+        if key_check.as_deref() != Some(KEY_CHECK_PLAINTEXT) {
+            return Err("wrong passphrase".to_string());
+        }
+        Ok(Some(cipher))
+    };
+    if let Some(password) = password {
+        return unlock(password);
+    }
+    if let Some(stored) = keyring.then(|| keyring_passphrase(template_name)).flatten() {
+        match unlock(&stored) {
+            Ok(cipher) => return Ok(cipher),
+            Err(err) => eprintln!("Warning: the passphrase in the system keyring for template '{}' does not work ({}).", template_name, err),
+        }
+    }
+    unlock(&read_passphrase(&format!("Passphrase for template '{}'", template_name), false)?)
+}
+
+#[cfg(unix)]
+fn file_owner(metadata: &fs::Metadata) -> Option<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.uid(), metadata.gid()))
+}
+
+#[cfg(not(unix))]
+fn file_owner(_metadata: &fs::Metadata) -> Option<(u32, u32)> {
+    None
+}
+
+#[cfg(unix)]
+fn restore_owner(path: &Path, (uid, gid): (u32, u32)) -> std::io::Result<()> {
+    std::os::unix::fs::chown(path, Some(uid), Some(gid))
+}
+
+#[cfg(not(unix))]
+fn restore_owner(_path: &Path, _owner: (u32, u32)) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "file ownership is only supported on Unix"))
+}
+
+#[cfg(unix)]
+fn current_owner() -> (u32, u32) {
+    unsafe { (libc::geteuid(), libc::getegid()) }
+}
+
+#[cfg(not(unix))]
+fn current_owner() -> (u32, u32) {
+    (0, 0)
+}
+
+type OwnerMapping = ((u32, u32), (u32, u32));
+
+fn map_owner(owner: (u32, u32), owner_map: Option<&[OwnerMapping]>) -> (u32, u32) {
+    match owner_map {
+        Some(owner_map) => owner_map.iter()
+            .find(|(from, _)| *from == owner)
+            .map(|(_, to)| *to)
+            .unwrap_or_else(current_owner),
+        None => owner,
+    }
+}
+
+#[cfg(unix)]
+fn apply_file_mode(path: &Path, chmod: Option<u32>, umask: Option<u32>) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = match (chmod, umask) {
+        (Some(mode), _) => mode,
+        (None, Some(mask)) => fs::metadata(path)?.permissions().mode() & 0o7777 & !mask,
+        (None, None) => return Ok(()),
+    };
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn apply_file_mode(_path: &Path, _chmod: Option<u32>, _umask: Option<u32>) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn file_xattrs(path: &Path) -> Vec<(String, Vec<u8>)> {
+    let Ok(names) = xattr::list(path) else {
+        return Vec::new();
+    };
+    names
+        .filter_map(|name| {
+            let value = xattr::get(path, &name).ok()??;
+            Some((name.to_string_lossy().to_string(), value))
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+fn file_xattrs(_path: &Path) -> Vec<(String, Vec<u8>)> {
+    Vec::new()
+}
+
+#[cfg(unix)]
+fn restore_xattr(path: &Path, name: &str, value: &[u8]) -> std::io::Result<()> {
+    xattr::set(path, name, value)
+}
+
+#[cfg(not(unix))]
+fn restore_xattr(_path: &Path, _name: &str, _value: &[u8]) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "extended attributes are only supported on Unix"))
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CachedFile {
+    modified_secs: u64,
+    modified_nanos: u32,
+    size: u64,
+    encoding: String,
+    #[serde(default)]
+    compressed: bool,
+    hash: String,
+    body: String,
+}
+
+type CreateCache = std::collections::HashMap<String, CachedFile>;
+
+fn create_cache_path(root_dir: &Path) -> PathBuf {
+    let config_file = get_config_file_path();
+    let key = sha256_hex(root_dir.to_string_lossy().as_bytes());
+    config_file.parent().unwrap_or(Path::new(".")).join("cache").join(format!("{}.json", &key[..16]))
+}
+
+fn read_create_cache(root_dir: &Path) -> CreateCache {
+    fs::read(create_cache_path(root_dir)).ok()
+        .and_then(|content| serde_json::from_slice(&content).ok())
+        .unwrap_or_default()
+}
+
+fn write_create_cache(root_dir: &Path, cache: &CreateCache) {
+    let path = create_cache_path(root_dir);
+    let written = path.parent().map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&path, serde_json::to_vec(cache).unwrap()));
+    if let Err(err) = written {
+        eprintln!("Warning: could not write the create cache '{}': {}", path.display(), err);
+    }
+}
+
+fn cache_stamp(file_path: &Path) -> Option<(u64, u32, u64)> {
+    let metadata = fs::metadata(file_path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(std::time::UNIX_EPOCH).ok()?;
+    Some((modified.as_secs(), modified.subsec_nanos(), metadata.len()))
+}
+
+fn process_file_cached(file_path: &Path, root_dir: &Path, options: &CreateOptions, text_attributes: &TextAttributes, cache: &CreateCache) -> Result<(TemplateFile, Option<CachedFile>, bool), String> {
+    let key = file_path.to_string_lossy().to_string();
+    let stamp = cache_stamp(file_path);
+    let hit = stamp.zip(cache.get(&key)).filter(|((secs, nanos, size), cached)| {
+        cached.modified_secs == *secs && cached.modified_nanos == *nanos && cached.size == *size
+            && cached.encoding == options.encoding.name() && cached.compressed == options.compress
+    });
+    if let Some((_, cached)) = hit {
+        let mut file = process_file_metadata(file_path, root_dir, options, text_attributes);
+        file.size = Some(cached.body.len());
+        file.hash = Some(cached.hash.clone());
+        file.length = cached.compressed.then_some(cached.size);
+        file.body = cached.body.clone();
+        return Ok((file, Some(cached.clone()), true));
+    }
+    let file = process_file(file_path, root_dir, options, text_attributes)?;
+    let cached = stamp.map(|(modified_secs, modified_nanos, size)| CachedFile {
+        modified_secs,
+        modified_nanos,
+        size,
+        encoding: options.encoding.name().to_string(),
+        compressed: options.compress,
+        hash: file.hash.clone().unwrap_or_default(),
+        body: file.body.clone(),
+    });
+    Ok((file, cached, false))
+}
+
+fn process_file_metadata(file_path: &Path, root_dir: &Path, options: &CreateOptions, text_attributes: &TextAttributes) -> TemplateFile {
+    let relative = file_path.strip_prefix(root_dir).unwrap();
+    if !options.byte_paths && relative.to_str().is_none() {
+        eprintln!("Warning: the name of {} is not valid UTF-8 and is stored with replacement characters; use --byte-paths to keep it exactly.",
+            relative.display());
+    }
+    let owner = if options.preserve_owner {
+        fs::metadata(file_path).ok().as_ref().and_then(file_owner)
+    } else {
+        None
+    };
+    TemplateFile {
+        path: path_text(relative, options.byte_paths),
+        owner,
+        xattrs: if options.preserve_xattrs { file_xattrs(file_path) } else { Vec::new() },
+        text: text_attributes.classify(relative),
+        env: options.env_tags.iter().find(|(_, glob)| glob.is_match(relative)).map(|(env, _)| env.clone()),
+        ..TemplateFile::default()
+    }
+}
+
+fn process_file(file_path: &Path, root_dir: &Path, options: &CreateOptions, text_attributes: &TextAttributes) -> Result<TemplateFile, String> {
+    let mut content = Vec::new();
+    if !options.skeleton {
+        File::open(file_path)
+            .and_then(|mut file| file.read_to_end(&mut content))
+            .map_err(|err| format!("{}: {}", file_path.strip_prefix(root_dir).unwrap_or(file_path).display(), err))?;
+    }
+    let encoded = encode_file(&content, options);
+    Ok(TemplateFile {
+        size: encoded.size,
+        hash: encoded.hash,
+        length: encoded.length,
+        nonce: encoded.nonce,
+        body: encoded.body,
+        ..process_file_metadata(file_path, root_dir, options, text_attributes)
+    })
+}
+
+fn encode_file(content: &[u8], options: &CreateOptions) -> TemplateFile {
+    let stored = match options.compress {
+        true => std::borrow::Cow::Owned(gzip(content).expect("gzip into memory cannot fail")),
+        false => std::borrow::Cow::Borrowed(content),
+    };
+    let length = (options.compress || options.encryption.is_some()).then_some(content.len() as u64);
+    if let Some(cipher) = &options.encryption {
+        let (nonce, ciphertext) = cipher.encrypt(&stored);
+        let body = options.encoding.encode(&ciphertext);
+        return TemplateFile {
+            size: Some(body.len()),
+            length,
+            nonce: Some(nonce),
+            body,
+            ..TemplateFile::default()
+        };
+    }
+    let encrypted_content = xor_crypt(&stored, KEY);
+    let body = options.encoding.encode(&encrypted_content);
+    TemplateFile {
+        size: Some(body.len()),
+        hash: Some(sha256_hex(content)),
+        length,
+        body,
+        ..TemplateFile::default()
+    }
+}
+
+fn read_stdin_file(relative_path: &str, options: &CreateOptions) -> Result<TemplateFile, String> {
+    let mut content = Vec::new();
+    std::io::stdin().read_to_end(&mut content).map_err(|err| format!("stdin: {}", err))?;
+    if options.skeleton {
+        content.clear();
+    }
+    Ok(TemplateFile { path: relative_path.to_string(), ..encode_file(&content, options) })
+}
+
+#[derive(Default)]
+struct TemplateFile {
+    path: String,
+    size: Option<usize>,
+    hash: Option<String>,
+    // The line of the FILE entry, in a template that was read from a file.
+    line: usize,
+    // The size of the content, for files whose body is compressed or encrypted.
+    length: Option<u64>,
+    owner: Option<(u32, u32)>,
+    xattrs: Vec<(String, Vec<u8>)>,
+    text: Option<bool>,
+    env: Option<String>,
+    blob: Option<String>,
+    nonce: Option<String>,
+    extra: Vec<(String, String)>,
+    body: String,
+}
+
+impl TemplateFile {
+    // The size of the content, without decoding it. None for a compressed body that has no LENGTH.
+    fn content_len(&self, codec: BodyCodec) -> Option<u64> {
+        if self.length.is_some() || (codec.compressed && self.blob.is_none()) {
+            return self.length;
+        }
+        let len = codec.encoding.decoded_len(&self.body);
+        Some(match self.nonce {
+            Some(_) => len.saturating_sub(AES_GCM_TAG_LEN),
+            None => len,
+        })
+    }
+}
+
+fn parse_owner(value: &str) -> Option<(u32, u32)> {
+    let (uid, gid) = value.trim().split_once(':')?;
+    Some((uid.parse().ok()?, gid.parse().ok()?))
+}
+
+enum TemplateEntry {
+    Dir(String),
+    File(Box<TemplateFile>),
+    Delete(String),
+}
+
+fn is_safe_relative_path(path: &Path) -> bool {
+    path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+fn parse_metadata_line(line: &str) -> Option<(&str, &str)> {
+    let (key, value) = line.split_once(": ")?;
+    if !key.is_empty() && key.chars().all(|c| c.is_ascii_uppercase() || c == '_') {
+        Some((key, value))
+    } else {
+        None
+    }
+}
+
+pub(crate) struct Template {
+    format_version: u32,
+    header: Vec<(String, String)>,
+    entries: Vec<TemplateEntry>,
+}
+
+impl Template {
+    fn header_value(&self, key: &str) -> Option<&str> {
+        self.header.iter().find(|(k, _)| k == key).map(|(_, value)| value.as_str())
+    }
+
+    fn encoding(&self) -> Encoding {
+        self.header_value("ENCODING").and_then(Encoding::parse).unwrap_or(Encoding::Base64)
+    }
+
+    fn byte_paths(&self) -> bool {
+        self.header_value("PATHS") == Some(BYTE_PATHS)
+    }
+
+    fn encrypted(&self) -> bool {
+        self.header_value("ENCRYPTION").is_some()
+    }
+
+    fn compressed(&self) -> bool {
+        self.header_value("COMPRESSION") == Some(COMPRESSION)
+    }
+
+    fn codec(&self) -> BodyCodec<'static> {
+        BodyCodec { encoding: self.encoding(), compressed: self.compressed(), cipher: None }
+    }
+}
+
+// Why a template can't be read or applied. The variants about the content of a template name the line (and the
+// file entry) where reading stopped, so a template damaged in transit or by hand can be located.
+#[derive(Debug)]
+pub(crate) enum TemplateError {
+    InvalidName { name: String, reason: String },
+    NotFound { name: String },
+    Unreadable { reason: String },
+    NotUtf8 { line: usize },
+    InvalidFormat { text: String },
+    NewerFormat { version: u32 },
+    UnsupportedHeader { line: usize, key: String, value: String },
+    UnsafePath { kind: &'static str, line: usize, path: String },
+    BadPercentPath { line: usize, path: String },
+    UnexpectedEof { path: String, line: usize },
+    InvalidSize { path: String, line: usize, text: String },
+    MalformedMetadata { path: String, line: usize, key: String, text: String },
+    BadContent { path: String, line: usize, encoding: &'static str },
+    MissingEndOfFile { path: String, line: usize },
+    MissingEndOfDir { path: String, line: usize },
+    MissingBlob { path: String, line: usize, hash: String, reason: String },
+    DamagedContent { path: String, line: usize, reason: String },
+    Locked { reason: String },
+    // apply_template stopped for a reason it already reported (conflicts, --strict, failed writes, ...).
+    NotApplied,
+}
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TemplateError::InvalidName { name, reason } => write!(f, "invalid template name '{}': {}", name.escape_debug(), reason),
+            TemplateError::NotFound { name } => write!(f, "template '{}' not found", name),
+            TemplateError::Unreadable { reason } => f.write_str(reason),
+            TemplateError::NotUtf8 { line } => write!(f, "the template is not valid UTF-8 (line {})", line),
+            TemplateError::InvalidFormat { text } => write!(f, "invalid TDMCLI_FORMAT '{}' on line 1", text),
+            TemplateError::NewerFormat { version } => write!(f,
+                "template was made with a newer tdmcli (format version {}, this tdmcli supports up to {}); please upgrade",
+                version, FORMAT_VERSION),
+            TemplateError::UnsupportedHeader { line, key, value } => write!(f, "unsupported {} '{}' on line {}", key, value, line),
+            TemplateError::UnsafePath { kind, line, path } => write!(f, "unsafe {} path on line {}: '{}'", kind, line, path),
+            TemplateError::BadPercentPath { line, path } => write!(f, "malformed percent-encoded path on line {}: '{}'", line, path),
+            TemplateError::UnexpectedEof { path, line } =>
+                write!(f, "unexpected end of template in the entry for file {} (line {})", path, line),
+            TemplateError::InvalidSize { path, line, text } => write!(f, "malformed SIZE for file {} on line {}: '{}'", path, line, text),
+            TemplateError::MalformedMetadata { path, line, key, text } =>
+                write!(f, "malformed {} for file {} on line {}: '{}'", key, path, line, text),
+            TemplateError::BadContent { path, line, encoding } =>
+                write!(f, "the content of file {} on line {} is not valid {}", path, line, encoding),
+            TemplateError::MissingEndOfFile { path, line } => write!(f, "expected END_OF_FILE on line {} after file {}", line, path),
+            TemplateError::MissingEndOfDir { path, line } => write!(f, "expected END_OF_DIR after directory {} on line {}", path, line),
+            TemplateError::MissingBlob { path, line, hash, reason } =>
+                write!(f, "the content of file {} (line {}, blob {}) cannot be read: {}", path, line, hash, reason),
+            TemplateError::DamagedContent { path, line, reason } =>
+                write!(f, "the content of file {} (line {}) is damaged: {}", path, line, reason),
+            TemplateError::Locked { reason } => write!(f, "cannot decrypt it: {}", reason),
+            TemplateError::NotApplied => f.write_str("the template was not applied"),
+        }
+    }
+}
+
+// Prints `err` the way the commands report a template they can't use.
+fn report_template_error(template_name: &str, err: &TemplateError) {
+    match err {
+        TemplateError::NotApplied => {}
+        TemplateError::InvalidName { name, reason } => eprintln!("Invalid template name '{}': {}.", name.escape_debug(), reason),
+        TemplateError::NotFound { name } => eprintln!("Template '{}' not found.", name),
+        TemplateError::Locked { reason } => eprintln!("Cannot decrypt template '{}': {}.", template_name, reason),
+        _ => eprintln!("Cannot read template '{}': {}.", template_name, err),
+    }
+}
+
+fn parse_body(file: &mut TemplateFile, line_number: usize, body: String, encoding: Encoding) -> Result<(), TemplateError> {
+    if !encoding.is_valid(&body) {
+        return Err(TemplateError::BadContent { path: file.path.clone(), line: line_number, encoding: encoding.name() });
+    }
+    file.body = body;
+    Ok(())
+}
+
+fn parse_legacy_file_block<'a>(file: &mut TemplateFile, entry_line: usize, lines: &mut impl Iterator<Item = (usize, &'a str)>) -> Result<(), TemplateError> {
+    let end_of_template = |file: &TemplateFile| TemplateError::UnexpectedEof { path: file.path.clone(), line: entry_line };
+    let (line_number, size_line) = lines.next().ok_or_else(|| end_of_template(file))?;
+    let invalid_size = |file: &TemplateFile| TemplateError::InvalidSize { path: file.path.clone(), line: line_number, text: size_line.to_string() };
+    let size = size_line.strip_prefix("SIZE: ").ok_or_else(|| invalid_size(file))?;
+    file.size = Some(size.trim().parse().map_err(|_| invalid_size(file))?);
+    let (line_number, body) = lines.next().ok_or_else(|| end_of_template(file))?;
+    parse_body(file, line_number, body.to_string(), Encoding::Base64)
+}
+
+fn parse_file_block<'a>(file: &mut TemplateFile, entry_line: usize, encoding: Encoding,
+                        lines: &mut std::iter::Peekable<impl Iterator<Item = (usize, &'a str)>>) -> Result<(), TemplateError> {
+    loop {
+        let (line_number, line) = lines.next()
+            .ok_or_else(|| TemplateError::UnexpectedEof { path: file.path.clone(), line: entry_line })?;
+        let malformed = |file: &TemplateFile, key: &str| TemplateError::MalformedMetadata {
+            path: file.path.clone(),
+            line: line_number,
+            key: key.to_string(),
+            text: line.to_string(),
+        };
+        match parse_metadata_line(line) {
+            Some(("SIZE", value)) => {
+                file.size = Some(value.trim().parse()
+                    .map_err(|_| TemplateError::InvalidSize { path: file.path.clone(), line: line_number, text: line.to_string() })?);
+            }
+            Some(("HASH", value)) => file.hash = Some(value.trim().to_string()),
+            Some(("LENGTH", value)) => {
+                file.length = Some(value.trim().parse().map_err(|_| malformed(file, "LENGTH"))?);
+            }
+            Some(("OWNER", value)) => {
+                file.owner = Some(parse_owner(value).ok_or_else(|| malformed(file, "OWNER"))?);
+            }
+            Some(("XATTR", value)) => {
+                let xattr = value.rsplit_once(' ')
+                    .and_then(|(name, value)| Some((name.to_string(), general_purpose::STANDARD.decode(value).ok()?)));
+                let xattr = xattr.ok_or_else(|| malformed(file, "XATTR"))?;
+                file.xattrs.push(xattr);
+            }
+            Some(("TYPE", value)) => {
+                file.text = Some(match value.trim() {
+                    "text" => true,
+                    "binary" => false,
+                    _ => return Err(malformed(file, "TYPE")),
+                });
+            }
+            Some(("ENV", value)) if !value.trim().is_empty() => file.env = Some(value.trim().to_string()),
+            Some(("BLOB", value)) => {
+                let hash = value.trim();
+                if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)) {
+                    return Err(malformed(file, "BLOB"));
+                }
+                file.blob = Some(hash.to_string());
+            }
+            Some(("NONCE", value)) => file.nonce = Some(value.trim().to_string()),
+            Some((key, value)) => file.extra.push((key.to_string(), value.to_string())),
+            // Base64 never contains ':', so such a line is a damaged metadata line, not content.
+            None if line.contains(':') => {
+                let key = line.split(':').next().unwrap_or_default().trim();
+                return Err(malformed(file, if key.is_empty() { "metadata" } else { key }));
+            }
+            None => {
+                let mut body = line.to_string();
+                // Wrapped bodies continue until END_OF_FILE; encoded content never contains ':' or spaces.
+                while let Some((_, next)) = lines.peek().filter(|(_, next)| *next != "END_OF_FILE" && !next.contains([':', ' '])) {
+                    body.push_str(next);
+                    lines.next();
+                }
+                return parse_body(file, line_number, body, encoding);
+            }
+        }
+    }
+}
+
+fn parse_entry_path<'a>(kind: &'static str, path: &'a str, line_number: usize, byte_paths: bool) -> Result<&'a str, TemplateError> {
+    if path.is_empty() || !is_safe_relative_path(Path::new(path)) {
+        return Err(TemplateError::UnsafePath { kind, line: line_number, path: path.to_string() });
+    }
+    if byte_paths && decode_path_bytes(path).is_none() {
+        return Err(TemplateError::BadPercentPath { line: line_number, path: path.to_string() });
+    }
+    Ok(path)
+}
+
+pub(crate) fn parse_template_bytes(content: &[u8]) -> Result<Template, TemplateError> {
+    let content = std::str::from_utf8(content).map_err(|err| TemplateError::NotUtf8 {
+        line: content[..err.valid_up_to()].iter().filter(|&&byte| byte == b'\n').count() + 1,
+    })?;
+    parse_template(content)
+}
+
+fn parse_template(content: &str) -> Result<Template, TemplateError> {
+    let mut entries = Vec::new();
+    let mut lines = content.lines().enumerate().map(|(index, line)| (index + 1, line)).peekable();
+
+    let format_version = match lines.peek().and_then(|(_, line)| line.strip_prefix("TDMCLI_FORMAT: ")) {
+        Some(value) => {
+            let version = value.trim().parse::<u32>()
+                .map_err(|_| TemplateError::InvalidFormat { text: value.to_string() })?;
+            lines.next();
+            version
+        }
+        None => 1,
+    };
+    if format_version > FORMAT_VERSION {
+        return Err(TemplateError::NewerFormat { version: format_version });
+    }
+    if format_version == 0 {
+        return Err(TemplateError::InvalidFormat { text: "0".to_string() });
+    }
+    let mut header: Vec<(String, String)> = Vec::new();
+    if format_version > 1 {
+        while let Some(&(line_number, line)) = lines.peek() {
+            let Some((key, value)) = parse_metadata_line(line).filter(|(key, _)| !["FILE", "DIR", "DELETE"].contains(key)) else {
+                break;
+            };
+            let supported = match key {
+                "ENCODING" => Encoding::parse(value).is_some(),
+                "WRAP" => value.trim().parse::<usize>().is_ok(),
+                "PATHS" => value == BYTE_PATHS,
+                "COMPRESSION" => value == COMPRESSION,
+                "ENCRYPTION" => value == ENCRYPTION,
+                _ => true,
+            };
+            if !supported {
+                return Err(TemplateError::UnsupportedHeader { line: line_number, key: key.to_string(), value: value.to_string() });
+            }
+            header.push((key.to_string(), value.to_string()));
+            lines.next();
+        }
+    }
+
+    let encoding = header.iter().find(|(key, _)| key == "ENCODING").and_then(|(_, value)| Encoding::parse(value)).unwrap_or(Encoding::Base64);
+    let byte_paths = header.iter().any(|(key, value)| key == "PATHS" && value == BYTE_PATHS);
+    while let Some((line_number, line)) = lines.next() {
+        if let Some(file_name) = line.strip_prefix("FILE: ") {
+            let mut file = TemplateFile {
+                path: parse_entry_path("FILE", file_name, line_number, byte_paths)?.to_string(),
+                line: line_number,
+                ..TemplateFile::default()
+            };
+            match format_version {
+                1 => parse_legacy_file_block(&mut file, line_number, &mut lines)?,
+                _ => parse_file_block(&mut file, line_number, encoding, &mut lines)?,
+            }
+            match lines.next() {
+                Some((_, "END_OF_FILE")) => {}
+                Some((end_number, _)) => return Err(TemplateError::MissingEndOfFile { path: file.path, line: end_number }),
+                None => return Err(TemplateError::UnexpectedEof { path: file.path, line: line_number }),
+            }
+            entries.push(TemplateEntry::File(Box::new(file)));
+        } else if let Some(dir_name) = line.strip_prefix("DIR: ") {
+            let dir_name = parse_entry_path("DIR", dir_name, line_number, byte_paths)?;
+            if !matches!(lines.next(), Some((_, "END_OF_DIR"))) {
+                return Err(TemplateError::MissingEndOfDir { path: dir_name.to_string(), line: line_number });
+            }
+            entries.push(TemplateEntry::Dir(dir_name.to_string()));
+        } else if let Some(path) = line.strip_prefix("DELETE: ").filter(|_| format_version > 1) {
+            entries.push(TemplateEntry::Delete(parse_entry_path("DELETE", path, line_number, byte_paths)?.to_string()));
+        }
+    }
+    Ok(Template { format_version, header, entries })
+}
+
+fn write_body(writer: &mut impl Write, body: &str, wrap: Option<usize>) -> std::io::Result<()> {
+    match wrap {
+        Some(columns) if body.len() > columns => {
+            for chunk in body.as_bytes().chunks(columns) {
+                writer.write_all(chunk)?;
+                writeln!(writer)?;
+            }
+            Ok(())
+        }
+        _ => writeln!(writer, "{}", body),
+    }
+}
+
+fn write_template(writer: &mut impl Write, template: &Template) -> std::io::Result<()> {
+    let legacy = template.format_version == 1;
+    let wrap = template.header_value("WRAP").and_then(|value| value.trim().parse::<usize>().ok()).filter(|&columns| columns > 0);
+    if !legacy {
+        writeln!(writer, "TDMCLI_FORMAT: {}", template.format_version)?;
+        for (key, value) in &template.header {
+            writeln!(writer, "{}: {}", key, value)?;
+        }
+    }
+    for entry in &template.entries {
+        match entry {
+            TemplateEntry::Dir(path) => {
+                writeln!(writer, "DIR: {}", path)?;
+                writeln!(writer, "END_OF_DIR")?;
+            }
+            TemplateEntry::Delete(_) if legacy => {}
+            TemplateEntry::Delete(path) => writeln!(writer, "DELETE: {}", path)?,
+            TemplateEntry::File(file) if legacy => {
+                writeln!(writer, "FILE: {}", file.path)?;
+                writeln!(writer, "SIZE: {}", file.body.len())?;
+                writeln!(writer, "{}", file.body)?;
+                writeln!(writer, "END_OF_FILE")?;
+            }
+            TemplateEntry::File(file) => {
+                writeln!(writer, "FILE: {}", file.path)?;
+                if let Some(size) = file.size {
+                    writeln!(writer, "SIZE: {}", size)?;
+                }
+                if let Some(hash) = &file.hash {
+                    writeln!(writer, "HASH: {}", hash)?;
+                }
+                if let Some(length) = file.length {
+                    writeln!(writer, "LENGTH: {}", length)?;
+                }
+                if let Some((uid, gid)) = file.owner {
+                    writeln!(writer, "OWNER: {}:{}", uid, gid)?;
+                }
+                for (name, value) in &file.xattrs {
+                    writeln!(writer, "XATTR: {} {}", name, general_purpose::STANDARD.encode(value))?;
+                }
+                if let Some(text) = file.text {
+                    writeln!(writer, "TYPE: {}", if text { "text" } else { "binary" })?;
+                }
+                if let Some(env) = &file.env {
+                    writeln!(writer, "ENV: {}", env)?;
+                }
+                if let Some(blob) = &file.blob {
+                    writeln!(writer, "BLOB: {}", blob)?;
+                }
+                if let Some(nonce) = &file.nonce {
+                    writeln!(writer, "NONCE: {}", nonce)?;
+                }
+                for (key, value) in &file.extra {
+                    writeln!(writer, "{}: {}", key, value)?;
+                }
+                write_body(writer, if file.blob.is_some() { "" } else { &file.body }, wrap)?;
+                writeln!(writer, "END_OF_FILE")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Used by the round_trip fuzz target: writes `content` as a single-file template in every format, encoding and
+// wrapping, parses it back and checks that decoding gives the exact same bytes (and the same for --byte-paths names).
+#[cfg(fuzzing)]
+pub(crate) fn check_round_trip(content: &[u8]) {
+    if !content.contains(&0) {
+        let encoded = encode_path_bytes(content);
+        assert!(!encoded.contains(['\n', '\r']));
+        assert_eq!(decode_path_bytes(&encoded).as_deref(), Some(content));
+    }
+    let hash = sha256_hex(content);
+    let mut variants = vec![(1, Vec::new())];
+    for encoding in [Encoding::Base64, Encoding::Base64Url, Encoding::Hex] {
+        for wrap in [None, Some(1), Some(3), Some(76)] {
+            let mut header = vec![("ENCODING".to_string(), encoding.name().to_string())];
+            if let Some(columns) = wrap {
+                header.push(("WRAP".to_string(), columns.to_string()));
+            }
+            variants.push((FORMAT_VERSION, header));
+        }
+        variants.push((FORMAT_VERSION, vec![
+            ("ENCODING".to_string(), encoding.name().to_string()),
+            ("COMPRESSION".to_string(), COMPRESSION.to_string()),
+        ]));
+    }
+    for (format_version, header) in variants {
+        let mut template = Template { format_version, header, entries: Vec::new() };
+        let compressed = template.compressed();
+        let stored = if compressed { gzip(content).unwrap() } else { content.to_vec() };
+        let body = template.encoding().encode(&xor_crypt(&stored, KEY));
+        template.entries = ["END_OF_FILE", "FILE: x", "DIR: y"].into_iter()
+            .map(|path| TemplateEntry::File(Box::new(TemplateFile {
+                path: path.to_string(),
+                size: Some(body.len()),
+                hash: Some(hash.clone()),
+                length: compressed.then_some(content.len() as u64),
+                body: body.clone(),
+                ..TemplateFile::default()
+            })))
+            .collect();
+        let mut written = Vec::new();
+        write_template(&mut written, &template).unwrap();
+        let parsed = parse_template_bytes(&written).unwrap();
+        assert_eq!(parsed.entries.len(), template.entries.len());
+        let codec = parsed.codec();
+        for (entry, expected) in parsed.entries.iter().zip(&template.entries) {
+            let (TemplateEntry::File(file), TemplateEntry::File(expected)) = (entry, expected) else {
+                panic!("entry read back as a different kind");
+            };
+            assert_eq!(file.path, expected.path);
+            let mut decoded = Vec::new();
+            let decoded_hash = decode_body(file, codec, &mut decoded).unwrap();
+            assert_eq!(decoded, content);
+            assert_eq!(decoded_hash, hash);
+        }
+    }
+
+    // `--encrypt` templates, with the cheapest key derivation since only the format is checked here.
+    static CIPHER: std::sync::OnceLock<TemplateCipher> = std::sync::OnceLock::new();
+    let cipher = CIPHER.get_or_init(|| {
+        TemplateCipher::derive("fuzz", b"tdmcli-fuzzing".to_vec(), argon2::Params::new(8, 1, 1, Some(32)).unwrap()).unwrap()
+    });
+    for compressed in [false, true] {
+        let mut header = cipher.header();
+        let (nonce, ciphertext) = match compressed {
+            true => {
+                header.push(("COMPRESSION".to_string(), COMPRESSION.to_string()));
+                cipher.encrypt(&gzip(content).unwrap())
+            }
+            false => cipher.encrypt(content),
+        };
+        let body = Encoding::Base64.encode(&ciphertext);
+        let file = Box::new(TemplateFile {
+            path: "FILE: x".to_string(),
+            size: Some(body.len()),
+            length: Some(content.len() as u64),
+            nonce: Some(nonce),
+            body,
+            ..TemplateFile::default()
+        });
+        let template = Template { format_version: FORMAT_VERSION, header, entries: vec![TemplateEntry::File(file)] };
+        let mut written = Vec::new();
+        write_template(&mut written, &template).unwrap();
+        let parsed = parse_template_bytes(&written).unwrap();
+        let unlocked = unlock_template("fuzz", &parsed, Some("fuzz"), false).unwrap();
+        let [TemplateEntry::File(file)] = parsed.entries.as_slice() else {
+            panic!("encrypted entry read back as a different kind");
+        };
+        let mut decoded = Vec::new();
+        let codec = BodyCodec { cipher: unlocked.as_ref(), ..parsed.codec() };
+        assert_eq!(decode_body(file, codec, &mut decoded).unwrap(), hash);
+        assert_eq!(decoded, content);
+    }
+}
+
+fn load_template(template_name: &str, templates_dir: &Path) -> Result<Template, TemplateError> {
+    validate_template_name(template_name)
+        .map_err(|reason| TemplateError::InvalidName { name: template_name.to_string(), reason })?;
+    let template_path = template_file_path(templates_dir, template_name);
+    if !template_path.exists() {
+        return Err(TemplateError::NotFound { name: template_name.to_string() });
+    }
+    let content = read_template_bytes(&template_path).map_err(|err| TemplateError::Unreadable { reason: err.to_string() })?;
+    let mut template = parse_template_bytes(&content)?;
+    load_blobs(&mut template, templates_dir)?;
+    Ok(template)
+}
+
+fn read_template(template_name: &str, templates_dir: &Path) -> Option<Template> {
+    load_template(template_name, templates_dir)
+        .map_err(|err| report_template_error(template_name, &err))
+        .ok()
+}
+
+fn blob_path(templates_dir: &Path, hash: &str) -> PathBuf {
+    templates_dir.join(BLOBS_DIR_NAME).join(hash)
+}
+
+// Files of `create --hash-names` templates keep their (obfuscated) content in the shared blob directory of the
+// templates directory, one blob per content hash; this fills their bodies in so the rest of tdmcli sees a normal template.
+fn load_blobs(template: &mut Template, templates_dir: &Path) -> Result<(), TemplateError> {
+    let encoding = template.encoding();
+    for entry in &mut template.entries {
+        if let TemplateEntry::File(file) = entry {
+            if let Some(hash) = &file.blob {
+                let content = fs::read(blob_path(templates_dir, hash)).map_err(|err| TemplateError::MissingBlob {
+                    path: file.path.clone(),
+                    line: file.line,
+                    hash: hash.clone(),
+                    reason: err.to_string(),
+                })?;
+                file.body = encoding.encode(&content);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Moves the content of every file into the blob directory. Returns the number of files whose content
+// was already there (from another template or an identical file) and the bytes this saved.
+fn store_blobs(template: &mut Template, templates_dir: &Path) -> std::io::Result<(usize, u64)> {
+    let encoding = template.encoding();
+    fs::create_dir_all(templates_dir.join(BLOBS_DIR_NAME))?;
+    let (mut shared, mut saved) = (0, 0);
+    for entry in &mut template.entries {
+        let TemplateEntry::File(file) = entry else {
+            continue;
+        };
+        let mut content = Vec::new();
+        encoding.reader(&file.body).read_to_end(&mut content)?;
+        let hash = file.hash.clone().unwrap_or_else(|| sha256_hex(&xor_crypt(&content, KEY)));
+        let path = blob_path(templates_dir, &hash);
+        if path.is_file() {
+            shared += 1;
+            saved += content.len() as u64;
+        } else {
+            let partial_path = path.with_extension("partial");
+            fs::write(&partial_path, &content)?;
+            fs::rename(&partial_path, &path)?;
+        }
+        file.hash = Some(hash.clone());
+        file.blob = Some(hash);
+    }
+    Ok((shared, saved))
+}
+
+// Removes the blobs that no template of the directory refers to any more.
+fn prune_blobs(templates_dir: &Path) -> usize {
+    let Ok(blobs) = fs::read_dir(templates_dir.join(BLOBS_DIR_NAME)) else {
+        return 0;
+    };
+    let mut referenced = std::collections::HashSet::new();
+    for name in template_names(templates_dir) {
+        let template = read_template_bytes(&template_file_path(templates_dir, &name)).map_err(|err| err.to_string())
+            .and_then(|content| parse_template_bytes(&content).map_err(|err| err.to_string()));
+        let Ok(template) = template else {
+            // Its blobs can't be known, so keep them all.
+            return 0;
+        };
+        referenced.extend(template.entries.into_iter().filter_map(|entry| match entry {
+            TemplateEntry::File(file) => file.blob,
+            _ => None,
+        }));
+    }
+    blobs.filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_str().is_none_or(|name| !referenced.contains(name)))
+        .filter(|entry| fs::remove_file(entry.path()).is_ok())
+        .count()
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static TEMPLATE_EXTENSION: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+static ASSUME_YES: std::sync::OnceLock<Option<bool>> = std::sync::OnceLock::new();
+
+// Asks a yes/no question on stderr. `-y`/`--assume-yes` answers yes, `--no-assume-yes` answers no
+// when stdin is not a terminal, and without either a non-interactive stdin answers yes.
+fn confirm(question: &str) -> bool {
+    use std::io::IsTerminal;
+    let assume_yes = ASSUME_YES.get().copied().flatten();
+    if assume_yes == Some(true) {
+        return true;
+    }
+    if !std::io::stdin().is_terminal() {
+        if assume_yes.is_none() {
+            return true;
+        }
+        eprintln!("{} [y/N] no (stdin is not a terminal and --no-assume-yes was given)", question);
+        return false;
+    }
+    eprint!("{} [y/N] ", question);
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+fn template_extension() -> &'static str {
+    TEMPLATE_EXTENSION.get_or_init(|| {
+        let Some(extension) = read_config().template_extension else {
+            return DEFAULT_TEMPLATE_EXTENSION.to_string();
+        };
+        let extension = extension.trim().trim_start_matches('.');
+        if extension.is_empty() || extension.contains(['/', '\\']) {
+            eprintln!("Warning: invalid template_extension '{}' in the config, using '{}'.", extension, DEFAULT_TEMPLATE_EXTENSION);
+            return DEFAULT_TEMPLATE_EXTENSION.to_string();
+        }
+        extension.to_string()
+    })
+}
+
+// Template names become file names in the templates directory, so they can't contain path separators
+// or anything else that would put the file somewhere else or that some file systems reject.
+fn validate_template_name(template_name: &str) -> Result<(), String> {
+    if template_name.is_empty() {
+        return Err("it is empty".to_string());
+    }
+    if template_name.starts_with('.') {
+        return Err("it starts with '.'".to_string());
+    }
+    if template_name.ends_with([' ', '.']) {
+        return Err("it ends with a space or '.'".to_string());
+    }
+    if let Some(c) = template_name.chars().find(|c| c.is_control() || r#"/\:*?"<>|"#.contains(*c)) {
+        return Err(format!("it contains {:?}", c));
+    }
+    if template_name.len() > 200 {
+        return Err("it is longer than 200 bytes".to_string());
+    }
+    Ok(())
+}
+
+fn check_template_name(template_name: &str) {
+    if let Err(reason) = validate_template_name(template_name) {
+        eprintln!("Invalid template name '{}': {}.", template_name.escape_debug(), reason);
+        eprintln!("Template names can use letters, digits, spaces and punctuation other than / \\ : * ? \" < > |.");
+        std::process::exit(1);
+    }
+}
+
+fn template_file_name(template_name: &str) -> String {
+    format!("{}.{}", template_name, template_extension())
+}
+
+fn gzip_file_name(template_name: &str) -> String {
+    format!("{}.{}", template_file_name(template_name), GZIP_EXTENSION)
+}
+
+fn is_gzip_path(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == GZIP_EXTENSION)
+}
+
+fn volume_path(base: &Path, index: usize) -> PathBuf {
+    let mut path = base.as_os_str().to_owned();
+    path.push(format!(".{:03}", index));
+    PathBuf::from(path)
+}
+
+fn is_first_volume(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == FIRST_VOLUME_EXTENSION)
+}
+
+// The contiguous run of volumes `<base>.001`, `<base>.002`, ... that exist next to the first one.
+fn volume_paths(first_volume: &Path) -> Vec<PathBuf> {
+    let base = first_volume.with_extension("");
+    (1..).map(|index| volume_path(&base, index)).take_while(|path| path.is_file()).collect()
+}
+
+// Every file on disk that makes up a stored template: one file, or all of its volumes.
+fn template_files(template_path: &Path) -> Vec<PathBuf> {
+    if is_first_volume(template_path) {
+        volume_paths(template_path)
+    } else if template_path.is_file() {
+        vec![template_path.to_path_buf()]
+    } else {
+        Vec::new()
+    }
+}
+
+// Splits `<name>.tdmcli`, `<name>.tdmcli.gz` or `<name>.tdmcli.001` into the template name and whether it is compressed.
+fn parse_template_file_name(file_name: &str) -> Option<(&str, bool)> {
+    let file_name = file_name.strip_suffix(&format!(".{}", FIRST_VOLUME_EXTENSION)).unwrap_or(file_name);
+    let (stem, gzip) = match file_name.strip_suffix(&format!(".{}", GZIP_EXTENSION)) {
+        Some(stem) => (stem, true),
+        None => (file_name, false),
+    };
+    let name = stem.strip_suffix(&format!(".{}", template_extension()))?;
+    (!name.is_empty()).then_some((name, gzip))
+}
+
+fn template_file_path(templates_dir: &Path, template_name: &str) -> PathBuf {
+    let plain = templates_dir.join(template_file_name(template_name));
+    let gzip = templates_dir.join(gzip_file_name(template_name));
+    let first_volume = volume_path(&plain, 1);
+    if plain.exists() {
+        plain
+    } else if gzip.exists() {
+        gzip
+    } else if first_volume.exists() {
+        first_volume
+    } else {
+        plain
+    }
+}
+
+fn read_template_bytes(path: &Path) -> std::io::Result<Vec<u8>> {
+    if is_first_volume(path) {
+        return read_volumes(path);
+    }
+    let content = fs::read(path)?;
+    if is_gzip_path(path) {
+        gunzip(&content)
+    } else {
+        Ok(content)
+    }
+}
+
+// Reassembles a split template and checks the VOLUMES header of the first volume against the volumes found.
+fn read_volumes(first_volume: &Path) -> std::io::Result<Vec<u8>> {
+    let paths = volume_paths(first_volume);
+    let mut content = Vec::new();
+    for path in &paths {
+        content.extend(fs::read(path)?);
+    }
+    let expected = content.split(|&byte| byte == b'\n')
+        .filter_map(|line| std::str::from_utf8(line).ok())
+        .take_while(|line| !line.starts_with("FILE: ") && !line.starts_with("DIR: ") && !line.starts_with("DELETE: "))
+        .find_map(|line| line.trim_end().strip_prefix("VOLUMES: "))
+        .and_then(|value| value.trim().parse::<usize>().ok());
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+    match expected {
+        Some(expected) if expected == paths.len() => Ok(content),
+        Some(expected) if expected > paths.len() => Err(invalid(format!(
+            "only {} of {} volumes were found, '{}' is missing",
+            paths.len(), expected, volume_path(&first_volume.with_extension(""), paths.len() + 1).display()))),
+        Some(expected) => Err(invalid(format!("found {} volumes but the template has only {}", paths.len(), expected))),
+        None => Err(invalid(format!("'{}' has no VOLUMES header", first_volume.display()))),
+    }
+}
+
+// Writes the template as volumes `<base>.001`, `<base>.002`, ... of at most `limit` bytes each and
+// removes leftover volumes of an earlier, larger split. Returns the number of volumes.
+fn write_template_volumes(base: &Path, template: &mut Template, limit: u64) -> std::io::Result<usize> {
+    let mut volumes = 1;
+    let content = loop {
+        template.header.retain(|(key, _)| key != "VOLUMES");
+        template.header.push(("VOLUMES".to_string(), volumes.to_string()));
+        let mut content = Vec::new();
+        write_template(&mut content, template)?;
+        let needed = (content.len() as u64).div_ceil(limit).max(1) as usize;
+        if needed == volumes {
+            break content;
+        }
+        volumes = needed;
+    };
+    let partials: Vec<(PathBuf, PathBuf)> = content.chunks(limit as usize).enumerate()
+        .map(|(index, chunk)| {
+            let path = volume_path(base, index + 1);
+            let mut partial = path.as_os_str().to_owned();
+            partial.push(".partial");
+            let partial = PathBuf::from(partial);
+            fs::write(&partial, chunk).map(|_| (partial, path))
+        })
+        .collect::<std::io::Result<_>>()?;
+    for (partial, path) in &partials {
+        fs::rename(partial, path)?;
+    }
+    let mut stale = volumes + 1;
+    while fs::remove_file(volume_path(base, stale)).is_ok() {
+        stale += 1;
+    }
+    Ok(volumes)
+}
+
+// Rewrites a stored template in place. A split template is joined back into a single file.
+fn replace_template_file(template_path: &Path, template: &mut Template) -> std::io::Result<()> {
+    if !is_first_volume(template_path) {
+        return write_template_file_atomically(template_path, template, is_gzip_path(template_path));
+    }
+    let joined = template_path.with_extension("");
+    template.header.retain(|(key, _)| key != "VOLUMES");
+    write_template_file_atomically(&joined, template, false)?;
+    for volume in volume_paths(template_path) {
+        let _ = fs::remove_file(volume);
+    }
+    eprintln!("Note: the split template was joined back into the single file '{}'.", joined.display());
+    Ok(())
+}
+
+// Writes next to the template and renames over it, so a failed write leaves the old template (and no .tmp file) behind.
+fn write_template_file_atomically(path: &Path, template: &Template, gzip: bool) -> std::io::Result<()> {
+    let temp_path = with_extension_suffix(path, "tmp");
+    let result = write_template_file(&temp_path, template, gzip).and_then(|_| fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+fn gunzip(content: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    flate2::read::GzDecoder::new(content).read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+fn write_template_file(path: &Path, template: &Template, gzip: bool) -> std::io::Result<()> {
+    let file = std::io::BufWriter::new(File::create(path)?);
+    if gzip {
+        let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+        write_template(&mut encoder, template)?;
+        encoder.finish()?.flush()
+    } else {
+        let mut file = file;
+        write_template(&mut file, template)?;
+        file.flush()
+    }
+}
+
+fn with_extension_suffix(path: &Path, suffix: &str) -> PathBuf {
+    path.with_extension(format!("{}.{}", template_extension(), suffix))
+}
+
+fn install_interrupt_handler() {
+    let _ = ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        eprintln!("\nInterrupted, cleaning up... (press Ctrl-C again to quit immediately)");
+    });
+}
+
+fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+fn new_progress_bar(len: u64, hidden: bool) -> ProgressBar {
+    if hidden {
+        return ProgressBar::hidden();
+    }
+    let pb = ProgressBar::new(len);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} files")
+        .unwrap());
+    pb
+}
+
+fn count_directories(entries: &[TemplateEntry]) -> usize {
+    let mut directories = std::collections::HashSet::new();
+    for entry in entries {
+        let path = match entry {
+            TemplateEntry::Dir(path) => Path::new(path),
+            TemplateEntry::File(file) => match Path::new(&file.path).parent() {
+                Some(parent) => parent,
+                None => continue,
+            },
+            TemplateEntry::Delete(_) => continue,
+        };
+        for ancestor in path.ancestors() {
+            if ancestor.as_os_str().is_empty() || !directories.insert(ancestor.to_path_buf()) {
+                break;
+            }
+        }
+    }
+    directories.len()
+}
+
+struct Timings {
+    enabled: bool,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Timings {
+    fn new(enabled: bool) -> Self {
+        Timings { enabled, phases: Vec::new() }
+    }
+
+    fn record(&mut self, phase: &'static str, duration: Duration) {
+        self.phases.push((phase, duration));
+    }
+
+    fn report(&self, operation: &str) {
+        if !self.enabled {
+            return;
+        }
+        for (phase, duration) in &self.phases {
+            eprintln!("timing: {} {}: {:.3}s", operation, phase, duration.as_secs_f64());
+        }
+    }
+}
+
+// Writes the phase timings, thread count, throughput and the size of every file of one create or get as JSON.
+fn write_profile(path: &Path, operation: &str, template_name: &str, timings: &Timings, files: &[(&str, u64)], elapsed: Duration) {
+    let bytes: u64 = files.iter().map(|(_, size)| size).sum();
+    let seconds = elapsed.as_secs_f64();
+    let per_second = |count: f64| if seconds > 0.0 { count / seconds } else { 0.0 };
+    let phases: serde_json::Map<String, serde_json::Value> = timings.phases.iter()
+        .map(|(phase, duration)| (phase.to_string(), duration.as_secs_f64().into()))
+        .collect();
+    let profile = serde_json::json!({
+        "operation": operation,
+        "template": template_name,
+        "tdmcli_version": VERSION,
+        "threads": rayon::current_num_threads(),
+        "elapsed_seconds": seconds,
+        "phases": phases,
+        "files": files.len(),
+        "bytes": bytes,
+        "bytes_per_second": per_second(bytes as f64),
+        "files_per_second": per_second(files.len() as f64),
+        "entries": files.iter().map(|(path, size)| serde_json::json!({ "path": path, "size": size })).collect::<Vec<_>>(),
+    });
+    if let Err(err) = fs::write(path, serde_json::to_string_pretty(&profile).unwrap() + "\n") {
+        eprintln!("Warning: could not write the profile '{}': {}", path.display(), err);
+    }
+}
+
+struct OperationSummary {
+    files: usize,
+    directories: usize,
+    bytes: u64,
+    elapsed: Duration,
+    template_bytes: Option<u64>,
+}
+
+fn print_summary(operation: &str, template_name: &str, summary: &OperationSummary, json: bool) {
+    let ratio = summary.template_bytes
+        .filter(|_| summary.bytes > 0)
+        .map(|template_bytes| template_bytes as f64 / summary.bytes as f64);
+    if json {
+        let mut value = serde_json::json!({
+            "operation": operation,
+            "template": template_name,
+            "files": summary.files,
+            "directories": summary.directories,
+            "bytes": summary.bytes,
+            "elapsed_seconds": summary.elapsed.as_secs_f64(),
+        });
+        if let Some(template_bytes) = summary.template_bytes {
+            value["template_bytes"] = template_bytes.into();
+            value["ratio"] = ratio.into();
+        }
+        println!("{}", value);
+        return;
+    }
+
+    let mut line = format!("{} files, {} directories, {} bytes in {:.2}s",
+        summary.files, summary.directories, summary.bytes, summary.elapsed.as_secs_f64());
+    if let Some(template_bytes) = summary.template_bytes {
+        line.push_str(&format!(", template size {} bytes", template_bytes));
+        if let Some(ratio) = ratio {
+            line.push_str(&format!(" (ratio {:.2})", ratio));
+        }
+    }
+    println!("{}", line);
+}
+
+#[derive(Default)]
+struct CreateOptions {
+    include_hidden: bool,
+    exclude_ignore: bool,
+    extensions: Vec<String>,
+    excluded_extensions: Vec<String>,
+    root_markers: Vec<String>,
+    exclude_vcs: bool,
+    extra_ignores: Vec<String>,
+    ignore_case: bool,
+    preserve_owner: bool,
+    preserve_xattrs: bool,
+    preserve_root_name: bool,
+    explain_ignores: bool,
+    format_version: u32,
+    encoding: Encoding,
+    output: Option<PathBuf>,
+    since: Option<String>,
+    dry_run: bool,
+    emit_summary: bool,
+    skeleton: bool,
+    text_only: bool,
+    wrap: Option<usize>,
+    cache: bool,
+    gzip: bool,
+    split: Option<u64>,
+    byte_paths: bool,
+    hash_names: bool,
+    encryption: Option<TemplateCipher>,
+    compress: bool,
+    skip_lfs: bool,
+    record_source: bool,
+    env_tags: Vec<(String, globset::GlobMatcher)>,
+    stdin_path: Option<String>,
+    exclude_symlinks: bool,
+    empty_dir_marker: Option<String>,
+    description: Option<String>,
+    from_readme: bool,
+    profile_json: Option<PathBuf>,
+    keep_going: bool,
+    watch: bool,
+    no_progress: bool,
+    timings: bool,
+    quiet: bool,
+    json: bool,
+}
+
+fn find_project_root(start_dir: &Path, markers: &[String]) -> Option<PathBuf> {
+    start_dir.ancestors()
+        .find(|dir| markers.iter().any(|marker| dir.join(marker).exists()))
+        .map(Path::to_path_buf)
+}
+
+fn normalize_extension(ext: &str) -> String {
+    ext.trim_start_matches('.').to_lowercase()
+}
+
+fn matches_extension_filter(path: &Path, options: &CreateOptions) -> bool {
+    let ext = path.extension()
+        .and_then(|s| s.to_str())
+        .map(normalize_extension)
+        .unwrap_or_default();
+
+    if !options.extensions.is_empty() && !options.extensions.contains(&ext) {
+        return false;
+    }
+    !options.excluded_extensions.contains(&ext)
+}
+
+struct CreatePlan {
+    files: Vec<PathBuf>,
+    empty_dirs: Vec<PathBuf>,
+    deleted: Vec<PathBuf>,
+    ignore_patterns: IgnorePatterns,
+}
+
+fn stage_in_git(output_dir: Option<&Path>, paths: &[PathBuf], chatty: bool) {
+    let current_dir = match output_dir {
+        Some(output_dir) => output_dir.to_path_buf(),
+        None => env::current_dir().unwrap(),
+    };
+    if run_git(&current_dir, &["rev-parse", "--is-inside-work-tree"]).is_err() {
+        eprintln!("Warning: '{}' is not inside a git repository, nothing was staged.", current_dir.display());
+        return;
+    }
+    let mut staged = 0;
+    for chunk in paths.chunks(GIT_ADD_BATCH) {
+        let mut args = vec!["add", "--all", "--"];
+        args.extend(chunk.iter().filter_map(|path| path.to_str()));
+        match run_git(&current_dir, &args) {
+            Ok(_) => staged += chunk.len(),
+            Err(err) => eprintln!("Warning: git add failed: {}", err),
+        }
+    }
+    if chatty {
+        println!("Staged {} paths with git add.", staged);
+    }
+}
+
+fn run_git(root_dir: &Path, args: &[&str]) -> Result<String, String> {
+    run_git_bytes(root_dir, args).map(|stdout| String::from_utf8_lossy(&stdout).to_string())
+}
+
+fn run_git_bytes(root_dir: &Path, args: &[&str]) -> Result<Vec<u8>, String> {
+    let output = std::process::Command::new("git")
+        .args(args)
+        .current_dir(root_dir)
+        .output()
+        .map_err(|err| format!("could not run git: {}", err))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(output.stdout)
+}
+
+// Paths listed with -z are NUL-separated and never quoted, whatever core.quotePath says.
+fn split_git_paths(output: &[u8]) -> Vec<PathBuf> {
+    output.split(|&byte| byte == 0)
+        .filter(|path| !path.is_empty())
+        .map(|path| path_from_bytes(path.to_vec()))
+        .collect()
+}
+
+fn git_changes_since(root_dir: &Path, git_ref: &str) -> (std::collections::HashSet<PathBuf>, Vec<PathBuf>) {
+    if run_git(root_dir, &["rev-parse", "--is-inside-work-tree"]).is_err() {
+        eprintln!("--since needs a git repository, but '{}' is not inside one.", root_dir.display());
+        std::process::exit(1);
+    }
+    let git_paths = |args: &[&str]| run_git_bytes(root_dir, args).map(|output| split_git_paths(&output)).unwrap_or_else(|err| {
+        eprintln!("Cannot list the changes since '{}': {}", git_ref, err);
+        std::process::exit(1);
+    });
+    let changed = git_paths(&["diff", "-z", "--name-only", "--relative", "--no-renames", "--diff-filter=d", git_ref, "--"]);
+    let untracked = git_paths(&["ls-files", "-z", "--others", "--exclude-standard"]);
+    let deleted = git_paths(&["diff", "-z", "--name-only", "--relative", "--no-renames", "--diff-filter=D", git_ref, "--"]);
+    let files = changed.iter().chain(&untracked)
+        .map(|path| root_dir.join(path))
+        .collect();
+    (files, deleted)
+}
+
+fn create_ignore_patterns(root_dir: &Path, options: &CreateOptions, chatty: bool) -> (PathBuf, IgnorePatterns) {
+    let ignore_root = if options.root_markers.is_empty() {
+        root_dir.to_path_buf()
+    } else if let Some(project_root) = find_project_root(root_dir, &options.root_markers) {
+        if chatty {
+            println!("Using '{}' as project root for .tdmignore resolution.", project_root.display());
+        }
+        project_root
+    } else {
+        if chatty {
+            println!("No project root marker found, using the current directory for .tdmignore resolution.");
+        }
+        root_dir.to_path_buf()
+    };
+    let mut extra_patterns: Vec<&str> = if options.exclude_vcs { VCS_DIRS.to_vec() } else { Vec::new() };
+    extra_patterns.extend(options.extra_ignores.iter().map(String::as_str));
+    let ignore_patterns = load_ignore_patterns(&ignore_root, &extra_patterns, options.ignore_case);
+    (ignore_root, ignore_patterns)
+}
+
+// Symlinks are never followed: a link to a file would inline its target and a link to a directory could
+// loop. Unless --exclude-symlinks says that is intended, every skipped link is listed.
+fn report_skipped_symlinks(root_dir: &Path, symlinks: &[walkdir::DirEntry], options: &CreateOptions, chatty: bool) {
+    if symlinks.is_empty() {
+        return;
+    }
+    if options.exclude_symlinks {
+        if chatty {
+            println!("Skipped {} symlinks.", symlinks.len());
+        }
+        return;
+    }
+    eprintln!("Warning: symlinks are not stored in templates, {} were skipped:", symlinks.len());
+    for symlink in symlinks {
+        let path = symlink.path();
+        let target = fs::read_link(path).map(|target| target.display().to_string()).unwrap_or_else(|_| "?".to_string());
+        eprintln!("- {} -> {}", path.strip_prefix(root_dir).unwrap_or(path).display(), target);
+    }
+    eprintln!("Pass --exclude-symlinks to skip them without this warning.");
+}
+
+fn plan_create(root_dir: &Path, options: &CreateOptions, in_store: &dyn Fn(&Path) -> bool, chatty: bool) -> CreatePlan {
+    let include_hidden = options.include_hidden;
+    let exclude_ignore = options.exclude_ignore;
+    let (ignore_root, ignore_patterns) = create_ignore_patterns(root_dir, options, chatty);
+
+    log::debug!("walking {}", root_dir.display());
+    let walker = walkdir::WalkDir::new(root_dir).into_iter().filter_entry(|entry| {
+        let path = entry.path();
+
+        if in_store(path) {
+            return false;
+        }
+        if !include_hidden && entry.file_type().is_dir() && is_hidden(path) {
+            log::debug!("skipping hidden directory {}", path.display());
+            return false;
+        }
+        true
+    });    
+
+    let (symlinks, file_entries): (Vec<walkdir::DirEntry>, Vec<walkdir::DirEntry>) = walker
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let path = entry.path();
+
+            if let Some(file_name) = path.file_name().and_then(|s| s.to_str()) {
+                if file_name == ".tdmignore" {
+                    return !exclude_ignore;
+                }
+            }
+
+            if should_ignore(path, &ignore_root, &ignore_patterns, exclude_ignore) {
+                return false;
+            }
+
+            log::trace!("walk: {}", path.display());
+            entry.file_type().is_file() || entry.path_is_symlink()
+        })
+        .partition(|entry| entry.path_is_symlink());
+    let file_entries: Vec<PathBuf> = file_entries.into_iter().map(walkdir::DirEntry::into_path).collect();
+    report_skipped_symlinks(root_dir, &symlinks, options, chatty);
+
+    let extension_filter_active = !options.extensions.is_empty() || !options.excluded_extensions.is_empty();
+    let (file_entries, filtered_out): (Vec<PathBuf>, Vec<PathBuf>) = file_entries
+        .into_iter()
+        .partition(|path| matches_extension_filter(path, options));
+    if extension_filter_active && chatty {
+        println!("Extension filter: {} files included, {} files excluded.", file_entries.len(), filtered_out.len());
+    }
+    let file_entries = if options.text_only {
+        let text_attributes = load_text_attributes(root_dir);
+        let (text, binary): (Vec<PathBuf>, Vec<PathBuf>) = file_entries.into_par_iter().partition(|path| {
+            let relative = path.strip_prefix(root_dir).unwrap_or(path);
+            text_attributes.classify(relative).unwrap_or_else(|| !file_is_binary(path))
+        });
+        if chatty {
+            println!("Text only: {} binary files skipped.", binary.len());
+        }
+        text
+    } else {
+        file_entries
+    };
+
+    let file_entries = if options.skip_lfs {
+        if run_git(root_dir, &["rev-parse", "--is-inside-work-tree"]).is_err() {
+            eprintln!("--skip-lfs needs a git repository, but '{}' is not inside one.", root_dir.display());
+            std::process::exit(1);
+        }
+        let lfs_attributes = load_attributes(root_dir, lfs_attribute);
+        let (mut lfs, others): (Vec<PathBuf>, Vec<PathBuf>) = file_entries.into_par_iter().partition(|path| {
+            let relative = path.strip_prefix(root_dir).unwrap_or(path);
+            lfs_attributes.classify(relative) == Some(true) || is_lfs_pointer(path)
+        });
+        if chatty && !lfs.is_empty() {
+            lfs.sort();
+            println!("Skipped {} git-lfs files:", lfs.len());
+            for path in &lfs {
+                println!("- {}", path.strip_prefix(root_dir).unwrap_or(path).display());
+            }
+        }
+        others
+    } else {
+        file_entries
+    };
+
+    if let Some(git_ref) = &options.since {
+        let (changed, deleted) = git_changes_since(root_dir, git_ref);
+        let file_entries: Vec<PathBuf> = file_entries.into_iter().filter(|path| changed.contains(path)).collect();
+        if chatty {
+            println!("{} files changed since '{}'.", file_entries.len(), git_ref);
+        }
+        return CreatePlan { files: file_entries, empty_dirs: Vec::new(), deleted, ignore_patterns };
+    }
+
+    let empty_dirs: Vec<PathBuf> = walkdir::WalkDir::new(root_dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let path = entry.path();
+            if !path.is_dir() || entry.path_is_symlink() || in_store(path) {
+                return false;
+            }
+
+            if !include_hidden {
+                if let Some(name) = path.file_name().and_then(|s| s.to_str()) {
+                    if name.starts_with('.') {
+                        return false;
+                    }
+                }
+            }
+
+            if should_ignore(path, &ignore_root, &ignore_patterns, exclude_ignore) {
+                return false;
+            }
+            if options.skeleton {
+                return true;
+            }
+
+            fs::read_dir(path)
+                .map(|mut iter| {
+                    iter.find(|entry| {
+                        if let Ok(entry) = entry {
+                            if entry.file_type().is_ok_and(|file_type| file_type.is_symlink()) {
+                                return false;
+                            }
+                            if !include_hidden {
+                                if let Some(name) = entry.file_name().to_str() {
+                                    return !name.starts_with('.');
+                                }
+                            }
+                            true
+                        } else {
+                            false
+                        }
+                    }).is_none()
+                })
+                .unwrap_or(false)
+        })
+        .map(|entry| entry.path().to_path_buf())
+        .collect();
+
+    CreatePlan { files: file_entries, empty_dirs, deleted: Vec::new(), ignore_patterns }
+}
+
+fn template_summary_path(template_path: &Path) -> PathBuf {
+    if is_gzip_path(template_path) || is_first_volume(template_path) {
+        return template_path.with_extension("").with_extension("json");
+    }
+    template_path.with_extension("json")
+}
+
+fn write_template_summary(template_name: &str, template_path: &Path, template: &Template) {
+    let codec = template.codec();
+    let files: Vec<serde_json::Value> = template.entries.iter()
+        .filter_map(|entry| match entry {
+            TemplateEntry::File(file) => Some(serde_json::json!({
+                "path": file.path,
+                "size": file.content_len(codec),
+                "hash": file.hash,
+            })),
+            _ => None,
+        })
+        .collect();
+    let created = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    let summary = serde_json::json!({
+        "template": template_name,
+        "tdmcli_version": VERSION,
+        "format_version": template.format_version,
+        "created_unix": created,
+        "files": files.len(),
+        "directories": count_directories(&template.entries),
+        "bytes": files.iter().filter_map(|file| file["size"].as_u64()).sum::<u64>(),
+        "entries": files,
+    });
+    let summary_path = template_summary_path(template_path);
+    if let Err(err) = fs::write(&summary_path, serde_json::to_string_pretty(&summary).unwrap() + "\n") {
+        eprintln!("Warning: could not write the summary '{}': {}", summary_path.display(), err);
+    }
+}
+
+fn print_create_plan(template_name: &str, root_dir: &Path, files: &[PathBuf], empty_dirs: &[PathBuf], deleted: &[PathBuf]) {
+    let relative = |path: &Path| path.strip_prefix(root_dir).unwrap_or(path).to_string_lossy().to_string();
+    let mut lines: Vec<(String, Option<u64>)> = files.iter()
+        .map(|file| (relative(file), Some(fs::metadata(file).map(|metadata| metadata.len()).unwrap_or(0))))
+        .chain(empty_dirs.iter().map(|dir| (relative(dir), None)).filter(|(path, _)| !path.is_empty()))
+        .collect();
+    lines.sort();
+    println!("Dry run, template '{}' would contain:", template_name);
+    for (path, size) in &lines {
+        match size {
+            Some(size) => println!("  {} ({} bytes)", path, size),
+            None => println!("  {}/", path),
+        }
+    }
+    for path in deleted {
+        println!("  {} (deleted)", path.display());
+    }
+    let total: u64 = lines.iter().filter_map(|(_, size)| *size).sum();
+    println!("{} files, {} empty directories, {} bytes, {} deletions. Nothing was written.",
+        files.len(), lines.len() - files.len(), total, deleted.len());
+}
+
+// The first paragraph of README.md in the source directory, on one line, skipping headings, badges and HTML.
+fn readme_description(root_dir: &Path) -> Option<String> {
+    let readme = fs::read_to_string(root_dir.join("README.md")).ok()?;
+    let paragraph: Vec<&str> = readme.lines()
+        .map(str::trim)
+        .skip_while(|line| line.is_empty() || line.starts_with('#') || line.starts_with("![") || line.starts_with("[![") || line.starts_with('<'))
+        .take_while(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    let description = one_line(&paragraph.join(" "));
+    (!description.is_empty()).then_some(description)
+}
+
+fn one_line(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn created_template_path(template_name: &str, templates_dir: &Path, options: &CreateOptions) -> PathBuf {
+    match &options.output {
+        Some(output) => output.clone(),
+        None if options.gzip => templates_dir.join(gzip_file_name(template_name)),
+        None => templates_dir.join(template_file_name(template_name)),
+    }
+}
+
+// Where the templates directory is inside the source directory, if it is; a template can't be made from inside it.
+fn store_in_source(store: &Path, source: &Path) -> Result<Option<PathBuf>, String> {
+    if source.starts_with(store) {
+        return Err(format!("Refusing to create a template from inside the templates directory '{}'.", store.display()));
+    }
+    Ok(store.strip_prefix(source).ok().map(Path::to_path_buf))
+}
+
+fn create_template(template_name: &str, root_dir: &Path, options: &CreateOptions) -> bool {
+    create_template_in(&get_templates_dir(), template_name, root_dir, options)
+}
+
+// Holds the store lock only while this template is written, so `create --watch` can call it for each rebuild.
+fn create_template_in(templates_dir: &Path, template_name: &str, root_dir: &Path, options: &CreateOptions) -> bool {
+    let started = Instant::now();
+    let mut timings = Timings::new(options.timings);
+    let chatty = !options.quiet && !options.json;
+    if chatty {
+        println!("Loading... Creating template '{}'.", template_name);
+    }
+    let _lock = lock_store_or_exit(templates_dir, true);
+    let template_path = created_template_path(template_name, templates_dir, options);
+    let store = fs::canonicalize(templates_dir).unwrap_or_else(|_| templates_dir.to_path_buf());
+    let source = fs::canonicalize(root_dir).unwrap_or_else(|_| root_dir.to_path_buf());
+    let store_in_tree = store_in_source(&store, &source).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        eprintln!("Run `tdmcli create` from the directory you want to turn into a template.");
+        std::process::exit(1);
+    });
+    if let Some(store_relative) = &store_in_tree {
+        eprintln!("Warning: the templates directory is inside the source directory, skipping '{}'.", store_relative.display());
+    }
+    let in_store = |path: &Path| {
+        path == template_path || store_in_tree.as_deref().is_some_and(|store_relative| {
+            path.strip_prefix(root_dir).is_ok_and(|relative| relative.starts_with(store_relative))
+        })
+    };
+    if options.format_version == 1 && options.encoding != Encoding::Base64 {
+        eprintln!("Format version 1 only supports base64, --encoding {} needs format version 2.", options.encoding.name());
+        std::process::exit(1);
+    }
+    if options.hash_names && (options.format_version == 1 || options.output.is_some()) {
+        eprintln!("--hash-names stores file contents in the templates directory, it needs format version 2 and cannot be combined with -o.");
+        std::process::exit(1);
+    }
+    if options.format_version == 1 && options.byte_paths {
+        eprintln!("--byte-paths needs format version 2.");
+        std::process::exit(1);
+    }
+    if options.format_version == 1 && options.encryption.is_some() {
+        eprintln!("--encrypt needs format version 2.");
+        std::process::exit(1);
+    }
+    if options.format_version == 1 && options.preserve_owner {
+        eprintln!("Warning: format version 1 cannot store file ownership, --preserve-owner is ignored.");
+    }
+    let root_name = options.preserve_root_name.then(|| match root_dir.file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => {
+            eprintln!("--preserve-root-name cannot be used in '{}', it has no directory name.", root_dir.display());
+            std::process::exit(1);
+        }
+    });
+    if options.format_version == 1 && root_name.is_some() {
+        eprintln!("Warning: format version 1 cannot record the root name, paths are prefixed with it but `get --strip-components` will not know about it.");
+    }
+    if options.preserve_owner && !cfg!(unix) {
+        eprintln!("Warning: --preserve-owner is only supported on Unix, file ownership will not be recorded.");
+    }
+    if options.split.is_some() && (options.gzip || options.format_version == 1) {
+        eprintln!("--split cannot be combined with --gzip or format version 1.");
+        std::process::exit(1);
+    }
+    if options.format_version == 1 && options.wrap.is_some() {
+        eprintln!("Warning: format version 1 stores each file on a single line, --wrap is ignored.");
+    }
+    if options.format_version == 1 && (options.description.is_some() || options.from_readme) {
+        eprintln!("Warning: format version 1 cannot store a description, --description and --from-readme are ignored.");
+    }
+    if options.format_version == 1 && !options.env_tags.is_empty() {
+        eprintln!("Warning: format version 1 cannot tag files with an environment, --env-tag is ignored.");
+    }
+    if options.format_version == 1 && options.preserve_xattrs {
+        eprintln!("Warning: format version 1 cannot store extended attributes, --preserve-xattrs is ignored.");
+    }
+    if options.preserve_xattrs && !cfg!(unix) {
+        eprintln!("Warning: --preserve-xattrs is only supported on Unix, extended attributes will not be recorded.");
+    }
+    let CreatePlan { files: file_entries, empty_dirs, deleted, ignore_patterns } = match options.stdin_path {
+        Some(_) => CreatePlan {
+            files: Vec::new(),
+            empty_dirs: Vec::new(),
+            deleted: Vec::new(),
+            ignore_patterns: IgnorePatterns { set: GlobSet::empty(), rule_of_glob: Vec::new(), rules: Vec::new(), hits: Vec::new() },
+        },
+        None => plan_create(root_dir, options, &in_store, chatty),
+    };
+    if !deleted.is_empty() && options.format_version == 1 {
+        eprintln!("Warning: format version 1 cannot record deletions, {} files deleted since '{}' are left out.",
+            deleted.len(), options.since.as_deref().unwrap_or_default());
+    }
+    timings.record("walk", started.elapsed());
+    if options.dry_run {
+        print_create_plan(template_name, root_dir, &file_entries, &empty_dirs, &deleted);
+        return true;
+    }
+
+    let processing_started = Instant::now();
+    let pb_files = new_progress_bar(file_entries.len() as u64, !chatty || options.no_progress);
+    let text_attributes = load_text_attributes(root_dir);
+    let use_cache = options.cache && !options.skeleton;
+    let cache = if use_cache { read_create_cache(root_dir) } else { CreateCache::new() };
+    let cache_hits = AtomicUsize::new(0);
+
+    let stop = AtomicBool::new(false);
+    let results: Vec<_> = file_entries.par_iter()
+        .filter_map(|file| {
+            if interrupted() || stop.load(Ordering::Relaxed) {
+                return None;
+            }
+            let res = if use_cache {
+                process_file_cached(file, root_dir, options, &text_attributes, &cache).map(|(res, cached, hit)| {
+                    if hit {
+                        cache_hits.fetch_add(1, Ordering::Relaxed);
+                    }
+                    (res, cached.map(|cached| (file.to_string_lossy().to_string(), cached)))
+                })
+            } else {
+                process_file(file, root_dir, options, &text_attributes).map(|res| (res, None))
+            };
+            if res.is_err() && !options.keep_going {
+                stop.store(true, Ordering::Relaxed);
+            }
+            pb_files.inc(1);
+            Some(res)
+        })
+        .collect();
+    let mut errors = Vec::new();
+    let (mut file_results, cached): (Vec<TemplateFile>, Vec<Option<(String, CachedFile)>>) = results.into_iter()
+        .filter_map(|res| res.map_err(|err| errors.push(err)).ok())
+        .unzip();
+    if let Some(relative_path) = &options.stdin_path {
+        match read_stdin_file(relative_path, options) {
+            Ok(file) => file_results.push(file),
+            Err(err) => errors.push(err),
+        }
+    }
+    if !errors.is_empty() && !options.keep_going {
+        pb_files.abandon();
+        report_errors(&errors, false);
+        eprintln!("Template '{}' was not created.", template_name);
+        std::process::exit(1);
+    }
+    if interrupted() {
+        pb_files.abandon();
+        eprintln!("Template '{}' was not created.", template_name);
+        std::process::exit(INTERRUPTED_EXIT_CODE);
+    }
+    pb_files.finish_with_message("File processing complete");
+    if use_cache {
+        write_create_cache(root_dir, &cached.into_iter().flatten().collect());
+        if options.explain_ignores && chatty && !file_results.is_empty() {
+            let hits = cache_hits.into_inner();
+            println!("Cache: {} of {} files reused ({:.0}%).", hits, file_results.len(), hits as f64 * 100.0 / file_results.len() as f64);
+        }
+    }
+    timings.record("read+encrypt", processing_started.elapsed());
+
+    let mut entries: Vec<TemplateEntry> = Vec::new();
+    for dir in empty_dirs {
+        let relative_path = path_text(dir.strip_prefix(root_dir).unwrap(), options.byte_paths);
+        if relative_path.is_empty() {
+            continue;
+        }
+        match &options.empty_dir_marker {
+            Some(marker) => entries.push(TemplateEntry::File(Box::new(TemplateFile {
+                path: Path::new(&relative_path).join(path_text(Path::new(marker), options.byte_paths)).to_string_lossy().to_string(),
+                ..encode_file(&[], options)
+            }))),
+            None => entries.push(TemplateEntry::Dir(relative_path)),
+        }
+    }
+    entries.extend(file_results.into_iter().map(|file| TemplateEntry::File(Box::new(file))));
+    entries.extend(deleted.into_iter().map(|path| TemplateEntry::Delete(path_text(&path, options.byte_paths))));
+
+    let mut header = Vec::new();
+    if options.encoding != Encoding::Base64 {
+        header.push(("ENCODING".to_string(), options.encoding.name().to_string()));
+    }
+    if let Some(columns) = options.wrap {
+        header.push(("WRAP".to_string(), columns.to_string()));
+    }
+    if options.byte_paths {
+        header.push(("PATHS".to_string(), BYTE_PATHS.to_string()));
+    }
+    if options.compress {
+        header.push(("COMPRESSION".to_string(), COMPRESSION.to_string()));
+    }
+    if let Some(cipher) = &options.encryption {
+        header.extend(cipher.header());
+    }
+    if options.record_source && options.format_version > 1 {
+        header.push(("SOURCE".to_string(), fs::canonicalize(root_dir).unwrap_or(root_dir.to_path_buf()).to_string_lossy().to_string()));
+    }
+    let description = options.description.clone()
+        .or_else(|| options.from_readme.then(|| readme_description(root_dir)).flatten());
+    if let Some(description) = description.filter(|_| options.format_version > 1) {
+        header.push(("DESCRIPTION".to_string(), description));
+    }
+    if let Some(root_name) = &root_name {
+        let root_path = path_text(Path::new(root_name), options.byte_paths);
+        let prefixed = |path: &str| Path::new(&root_path).join(path).to_string_lossy().to_string();
+        for entry in &mut entries {
+            match entry {
+                TemplateEntry::Dir(path) | TemplateEntry::Delete(path) => *path = prefixed(path),
+                TemplateEntry::File(file) => file.path = prefixed(&file.path),
+            }
+        }
+        if entries.is_empty() {
+            entries.push(TemplateEntry::Dir(root_path));
+        }
+        header.push(("ROOT_NAME".to_string(), root_name.clone()));
+    }
+
+    let mut template = Template { format_version: options.format_version, header, entries };
+    if options.hash_names {
+        let templates_dir = template_path.parent().unwrap_or(Path::new("."));
+        match store_blobs(&mut template, templates_dir) {
+            Ok((shared, saved)) if chatty => println!("{} of {} files shared their content with existing blobs ({} bytes saved).",
+                shared, template.entries.iter().filter(|entry| matches!(entry, TemplateEntry::File(_))).count(), saved),
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("Cannot store the file contents in '{}': {}", templates_dir.join(BLOBS_DIR_NAME).display(), err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let write_started = Instant::now();
+    let stored_path = match options.split {
+        Some(limit) => {
+            if interrupted() {
+                eprintln!("Template '{}' was not created.", template_name);
+                std::process::exit(INTERRUPTED_EXIT_CODE);
+            }
+            log::debug!("writing {} entries to volumes of {}", template.entries.len(), template_path.display());
+            let volumes = write_template_volumes(&template_path, &mut template, limit).unwrap();
+            if chatty {
+                println!("Split into {} volumes of at most {} bytes.", volumes, limit);
+            }
+            volume_path(&template_path, 1)
+        }
+        None => {
+            let partial_path = with_extension_suffix(&template_path, "partial");
+            log::debug!("writing {} entries to {}", template.entries.len(), partial_path.display());
+            write_template_file(&partial_path, &template, options.gzip || is_gzip_path(&template_path)).unwrap();
+            if interrupted() {
+                let _ = fs::remove_file(&partial_path);
+                eprintln!("Template '{}' was not created.", template_name);
+                std::process::exit(INTERRUPTED_EXIT_CODE);
+            }
+            fs::rename(&partial_path, &template_path).unwrap();
+            template_path.clone()
+        }
+    };
+    if options.output.is_none() {
+        for form in [template_file_name(template_name), gzip_file_name(template_name)] {
+            let form = template_path.with_file_name(form);
+            if options.split.is_some() || form != template_path {
+                let _ = fs::remove_file(form);
+            }
+        }
+        if options.split.is_none() {
+            for volume in volume_paths(&volume_path(&template_path.with_file_name(template_file_name(template_name)), 1)) {
+                let _ = fs::remove_file(volume);
+            }
+        }
+    }
+    timings.record("write", write_started.elapsed());
+    timings.report("create");
+    if options.emit_summary {
+        write_template_summary(template_name, &stored_path, &template);
+    }
+
+    if options.explain_ignores {
+        report_unmatched_ignore_patterns(&ignore_patterns);
+    }
+
+    if chatty {
+        match &options.output {
+            Some(output) => println!("Template '{}' written to {}.", template_name, output.display()),
+            None => println!("Template '{}' created successfully.", template_name),
+        }
+    }
+    if !options.quiet {
+        let files = template.entries.iter().filter_map(|entry| match entry {
+            TemplateEntry::File(file) => Some(file),
+            TemplateEntry::Dir(_) | TemplateEntry::Delete(_) => None,
+        });
+        let summary = OperationSummary {
+            files: files.clone().count(),
+            directories: count_directories(&template.entries),
+            bytes: files.filter_map(|file| file.content_len(template.codec())).sum(),
+            elapsed: started.elapsed(),
+            template_bytes: Some(template_files(&stored_path).iter()
+                .filter_map(|file| fs::metadata(file).ok())
+                .map(|metadata| metadata.len())
+                .sum()),
+        };
+        print_summary("create", template_name, &summary, options.json);
+        if let Some(template_bytes) = summary.template_bytes.filter(|&bytes| bytes >= SIZE_RATIO_MIN_BYTES && summary.bytes > 0) {
+            let ratio = template_bytes as f64 / summary.bytes as f64;
+            // Only --no-compress leaves contents uncompressed when compression is available at all.
+            let no_compress = !options.compress && options.format_version > 1 && !options.hash_names;
+            if no_compress && !options.gzip && !is_gzip_path(&stored_path) && ratio > SIZE_RATIO_WARNING {
+                eprintln!("Warning: the template is {:.2} times the size of its files ({} bytes for {}); creating it without --no-compress would make it smaller.",
+                    ratio, template_bytes, summary.bytes);
+            }
+        }
+    }
+    if let Some(profile) = &options.profile_json {
+        let files: Vec<(&str, u64)> = template.entries.iter()
+            .filter_map(|entry| match entry {
+                TemplateEntry::File(file) => Some((file.path.as_str(), file.content_len(template.codec()).unwrap_or_default())),
+                _ => None,
+            })
+            .collect();
+        write_profile(profile, "create", template_name, &timings, &files, started.elapsed());
+    }
+    if !errors.is_empty() {
+        eprintln!("Template '{}' was created without the {} files that could not be read.", template_name, errors.len());
+        report_errors(&errors, true);
+        return false;
+    }
+    true
+}
+
+fn watch_and_recreate(template_name: &str, root_dir: &Path, options: &CreateOptions) {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let mut watcher = match notify::recommended_watcher(sender) {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            eprintln!("Cannot watch '{}' for changes: {}", root_dir.display(), err);
+            std::process::exit(1);
+        }
+    };
+    if let Err(err) = notify::Watcher::watch(&mut watcher, root_dir, notify::RecursiveMode::Recursive) {
+        eprintln!("Cannot watch '{}' for changes: {}", root_dir.display(), err);
+        std::process::exit(1);
+    }
+
+    let templates_dir = get_templates_dir();
+    let template_path = created_template_path(template_name, &templates_dir, options);
+    let store = fs::canonicalize(&templates_dir).unwrap_or_else(|_| templates_dir.clone());
+    let (ignore_root, ignore_patterns) = create_ignore_patterns(root_dir, options, false);
+    let relevant = |path: &Path| {
+        let Ok(relative) = path.strip_prefix(root_dir) else {
+            return false;
+        };
+        if path.starts_with(&store) || path.starts_with(&template_path) || path == with_extension_suffix(&template_path, "partial") {
+            return false;
+        }
+        let hidden_dirs = if path.is_dir() { Some(relative) } else { relative.parent() };
+        if !options.include_hidden && hidden_dirs.is_some_and(|dirs| dirs.components().any(|component| {
+            component.as_os_str().to_str().is_some_and(|name| name.starts_with('.'))
+        })) {
+            return false;
+        }
+        if !path.is_dir() && !matches_extension_filter(path, options) {
+            return false;
+        }
+        !should_ignore(path, &ignore_root, &ignore_patterns, options.exclude_ignore)
+    };
+
+    println!("Watching '{}' for changes, press Ctrl-C to stop.", root_dir.display());
+    loop {
+        match receiver.recv_timeout(Duration::from_millis(200)) {
+            Ok(Ok(event)) if !event.kind.is_access() && event.paths.iter().any(|path| relevant(path)) => {}
+            Ok(_) => continue,
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) if !interrupted() => continue,
+            Err(_) => break,
+        }
+        while receiver.recv_timeout(WATCH_DEBOUNCE).is_ok() && !interrupted() {}
+        if interrupted() {
+            break;
+        }
+        println!("Change detected, recreating template '{}'.", template_name);
+        create_template_in(&templates_dir, template_name, root_dir, options);
+    }
+    println!("Stopped watching '{}'.", root_dir.display());
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum MergePreference {
+    Existing,
+    Template,
+}
+
+#[derive(Clone, Copy)]
+enum MergeFormat {
+    Json,
+    Toml,
+}
+
+fn merge_format(file_name: &str, options: &ApplyOptions) -> Option<MergeFormat> {
+    match Path::new(file_name).extension()?.to_str()? {
+        "json" if options.merge_json => Some(MergeFormat::Json),
+        "toml" if options.merge_toml => Some(MergeFormat::Toml),
+        _ => None,
+    }
+}
+
+fn merge_json(existing: &mut serde_json::Value, incoming: serde_json::Value, prefer: MergePreference) {
+    match (existing, incoming) {
+        (serde_json::Value::Object(existing), serde_json::Value::Object(incoming)) => {
+            for (key, value) in incoming {
+                match existing.get_mut(&key) {
+                    Some(current) => merge_json(current, value, prefer),
+                    None => {
+                        existing.insert(key, value);
+                    }
+                }
+            }
+        }
+        (existing, incoming) => {
+            if prefer == MergePreference::Template {
+                *existing = incoming;
+            }
+        }
+    }
+}
+
+fn merge_toml(existing: &mut toml::Value, incoming: toml::Value, prefer: MergePreference) {
+    match (existing, incoming) {
+        (toml::Value::Table(existing), toml::Value::Table(incoming)) => {
+            for (key, value) in incoming {
+                match existing.get_mut(&key) {
+                    Some(current) => merge_toml(current, value, prefer),
+                    None => {
+                        existing.insert(key, value);
+                    }
+                }
+            }
+        }
+        (existing, incoming) => {
+            if prefer == MergePreference::Template {
+                *existing = incoming;
+            }
+        }
+    }
+}
+
+fn merge_structured(format: MergeFormat, existing: &[u8], incoming: &[u8], prefer: MergePreference) -> Result<Vec<u8>, String> {
+    match format {
+        MergeFormat::Json => {
+            let mut merged: serde_json::Value = serde_json::from_slice(existing)
+                .map_err(|err| format!("the existing file is not valid JSON ({})", err))?;
+            let incoming: serde_json::Value = serde_json::from_slice(incoming)
+                .map_err(|err| format!("the template file is not valid JSON ({})", err))?;
+            merge_json(&mut merged, incoming, prefer);
+            Ok((serde_json::to_string_pretty(&merged).unwrap() + "\n").into_bytes())
+        }
+        MergeFormat::Toml => {
+            let parse = |content: &[u8], which: &str| {
+                std::str::from_utf8(content).ok()
+                    .and_then(|text| text.parse::<toml::Table>().ok())
+                    .map(toml::Value::Table)
+                    .ok_or_else(|| format!("the {} file is not valid TOML", which))
+            };
+            let mut merged = parse(existing, "existing")?;
+            merge_toml(&mut merged, parse(incoming, "template")?, prefer);
+            toml::to_string(&merged).map(String::into_bytes).map_err(|err| err.to_string())
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum UndefinedEnv {
+    Error,
+    Keep,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ConflictPolicy {
+    Overwrite,
+    SkipExisting,
+}
+
+#[derive(Clone)]
+struct ApplyOptions {
+    conflict_policy: ConflictPolicy,
+    backup: bool,
+    backup_dir: Option<PathBuf>,
+    only: Option<GlobSet>,
+    except: Option<GlobSet>,
+    interactive: bool,
+    strict: bool,
+    empty_dir_marker: Option<String>,
+    print_tree_after: bool,
+    protect: Option<GlobSet>,
+    profile_json: Option<PathBuf>,
+    templates_dir: Option<PathBuf>,
+    password: Option<String>,
+    keyring: bool,
+    output_dir: Option<PathBuf>,
+    prefix: Option<PathBuf>,
+    strip_components: usize,
+    expand_env: Option<UndefinedEnv>,
+    allow_absolute_env: bool,
+    skip_unchanged: bool,
+    merge_json: bool,
+    merge_toml: bool,
+    merge_prefer: MergePreference,
+    chmod: Option<u32>,
+    umask: Option<u32>,
+    owner_map: Option<Vec<OwnerMapping>>,
+    env: Option<String>,
+    git_add: bool,
+    dirs_only: bool,
+    check: bool,
+    keep_going: bool,
+    conflict_report: Option<PathBuf>,
+    overwrite_confirmed: bool,
+    update_lockfile: bool,
+    no_progress: bool,
+    timings: bool,
+    quiet: bool,
+    json: bool,
+}
+
+enum FileOutcome {
+    Written,
+    Created(PathBuf),
+    Interrupted,
+    Skipped,
+    Protected(PathBuf),
+    Unchanged,
+    BackedUp(PathBuf),
+    Failed(String),
+}
+
+fn matches_existing(path: &Path, file: &TemplateFile, codec: BodyCodec, transforms: &[Transform]) -> bool {
+    let transformed = transforms.iter().any(|transform| transform.matcher.is_match(&file.path));
+    if !transformed && file.content_len(codec).zip(fs::metadata(path).ok()).is_some_and(|(len, metadata)| metadata.len() != len) {
+        return false;
+    }
+    let Ok(existing) = fs::read(path) else {
+        return false;
+    };
+    if transformed {
+        let mut content = Vec::new();
+        return decode_body(file, codec, &mut content).is_ok()
+            && apply_transforms(&file.path, content, file.text, transforms) == existing;
+    }
+    let expected = match &file.hash {
+        Some(hash) => hash.clone(),
+        None => match decode_body(file, codec, &mut std::io::sink()) {
+            Ok(hash) => hash,
+            Err(_) => return false,
+        },
+    };
+    sha256_hex(&existing) == expected
+}
+
+// A new `<unix time>-<template name>` directory under `backup_dir` for the files overwritten by one get.
+fn backup_run_dir(backup_dir: &Path, template_name: &str) -> PathBuf {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let name = format!("{}-{}", now, template_name.replace(['/', '\\'], "_"));
+    let mut dir = backup_dir.join(&name);
+    let mut attempt = 1;
+    while dir.exists() {
+        attempt += 1;
+        dir = backup_dir.join(format!("{}-{}", name, attempt));
+    }
+    dir
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".tdmcli.partial");
+    PathBuf::from(partial)
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".tdmcli.bak");
+    PathBuf::from(backup)
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct LockFile {
+    #[serde(default)]
+    template: Vec<LockedTemplate>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LockedTemplate {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dir: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_dir: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strip_components: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    env: Option<String>,
+}
+
+fn read_lockfile() -> Option<LockFile> {
+    read_toml_file(Path::new(LOCKFILE_NAME))
+}
+
+fn record_in_lockfile(template_name: &str, options: &ApplyOptions) {
+    let mut lockfile = read_lockfile().unwrap_or_default();
+    let locked = LockedTemplate {
+        name: template_name.to_string(),
+        dir: options.templates_dir.as_ref().map(|dir| dir.to_string_lossy().to_string()),
+        output_dir: options.output_dir.as_ref().map(|dir| dir.to_string_lossy().to_string()),
+        prefix: options.prefix.as_ref().map(|prefix| prefix.to_string_lossy().to_string()),
+        strip_components: Some(options.strip_components).filter(|&count| count > 0),
+        env: options.env.clone(),
+    };
+    match lockfile.template.iter_mut().find(|entry| entry.name == template_name) {
+        Some(entry) => *entry = locked,
+        None => lockfile.template.push(locked),
+    }
+    if let Err(err) = fs::write(LOCKFILE_NAME, toml::to_string(&lockfile).unwrap()) {
+        eprintln!("Warning: could not update {}: {}", LOCKFILE_NAME, err);
+    }
+}
+
+fn apply_from_lockfile(options: &ApplyOptions) {
+    let Some(lockfile) = read_lockfile().filter(|lockfile| !lockfile.template.is_empty()) else {
+        println!("No template name given and no {} found in the current directory.", LOCKFILE_NAME);
+        println!("Use `tdmcli get <template_name>` to apply a template.");
+        std::process::exit(1);
+    };
+    for locked in lockfile.template {
+        let options = ApplyOptions {
+            templates_dir: locked.dir.map(PathBuf::from).or_else(|| options.templates_dir.clone()),
+            output_dir: locked.output_dir.map(PathBuf::from).or_else(|| options.output_dir.clone()),
+            prefix: locked.prefix.map(PathBuf::from).or_else(|| options.prefix.clone()),
+            strip_components: locked.strip_components.unwrap_or(options.strip_components),
+            env: locked.env.or_else(|| options.env.clone()),
+            ..options.clone()
+        };
+        if let Err(err) = apply_template(&locked.name, &options) {
+            report_template_error(&locked.name, &err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn expand_env(path: &str, undefined: &mut Vec<String>) -> String {
+    let mut expanded = String::new();
+    let mut rest = path;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, literal_len) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            },
+            None => {
+                let end = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(after.len());
+                (&after[..end], end)
+            }
+        };
+        if name.is_empty() {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        match env::var(name) {
+            Ok(value) => expanded.push_str(&value),
+            Err(_) => {
+                undefined.push(name.to_string());
+                expanded.push_str(&rest[start..start + 1 + literal_len]);
+            }
+        }
+        rest = &after[literal_len..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+// Paths are checked when the template is read, before expansion, so an expanded path is checked again: a variable
+// may not add '..', and may only make the path absolute (e.g. `$HOME/.config`) with --allow-absolute-env.
+fn is_safe_expanded_path(path: &Path, allow_absolute: bool) -> bool {
+    !path.as_os_str().is_empty() && path.components().all(|component| match component {
+        Component::Normal(_) | Component::CurDir => true,
+        Component::RootDir | Component::Prefix(_) => allow_absolute,
+        Component::ParentDir => false,
+    })
+}
+
+fn strip_components(path: &str, count: usize) -> Option<String> {
+    if count == 0 {
+        return Some(path.to_string());
+    }
+    let stripped: PathBuf = Path::new(path).components().skip(count).collect();
+    if stripped.as_os_str().is_empty() {
+        None
+    } else {
+        Some(stripped.to_string_lossy().to_string())
+    }
+}
+
+// Paths that have more than one FILE entry (for the same environment), in the order they first appear.
+fn duplicate_file_paths(entries: &[TemplateEntry]) -> Vec<String> {
+    let mut seen = std::collections::HashSet::new();
+    let mut duplicates = Vec::new();
+    for entry in entries {
+        if let TemplateEntry::File(file) = entry {
+            if !seen.insert((file.path.as_str(), file.env.as_deref())) && !duplicates.contains(&file.path) {
+                duplicates.push(file.path.clone());
+            }
+        }
+    }
+    duplicates
+}
+
+fn keep_last_file_entries(entries: Vec<TemplateEntry>) -> Vec<TemplateEntry> {
+    let mut seen = std::collections::HashSet::new();
+    let mut kept: Vec<TemplateEntry> = entries.into_iter().rev()
+        .filter(|entry| match entry {
+            TemplateEntry::File(file) => seen.insert(file.path.clone()),
+            _ => true,
+        })
+        .collect();
+    kept.reverse();
+    kept
+}
+
+fn entry_path(entry: &TemplateEntry) -> &str {
+    match entry {
+        TemplateEntry::Dir(path) | TemplateEntry::Delete(path) => path,
+        TemplateEntry::File(file) => &file.path,
+    }
+}
+
+// Lets the user uncheck entries of the template in a terminal selector; everything is checked at first.
+fn select_entries(template_name: &str, entries: Vec<TemplateEntry>) -> Option<Vec<TemplateEntry>> {
+    use std::io::IsTerminal;
+    if !std::io::stdin().is_terminal() || !std::io::stderr().is_terminal() {
+        eprintln!("--interactive needs a terminal; use --only <glob> and --except <glob> to choose files in scripts.");
+        return None;
+    }
+    let items: Vec<String> = entries.iter()
+        .map(|entry| match entry {
+            TemplateEntry::Dir(path) => format!("{}/", path),
+            TemplateEntry::File(file) => file.path.clone(),
+            TemplateEntry::Delete(path) => format!("{} (delete)", path),
+        })
+        .collect();
+    let selection = dialoguer::MultiSelect::new()
+        .with_prompt(format!("Entries of '{}' to apply (space toggles, enter confirms)", template_name))
+        .items(&items)
+        .defaults(&vec![true; items.len()])
+        .interact_opt();
+    let chosen = match selection {
+        Ok(Some(chosen)) => chosen,
+        Ok(None) => {
+            println!("Nothing was applied.");
+            return None;
+        }
+        Err(err) => {
+            eprintln!("Cannot show the selection: {}", err);
+            return None;
+        }
+    };
+    Some(entries.into_iter().enumerate()
+        .filter(|(index, _)| chosen.contains(index))
+        .map(|(_, entry)| entry)
+        .collect())
+}
+
+// `get --check`: runs every validation of a real apply (decoding, hashes, what is in the way, free space)
+// without writing anything, and returns whether the apply would succeed.
+fn check_apply(template_name: &str, entries: &[TemplateEntry], codec: BodyCodec, options: &ApplyOptions,
+    destination: &dyn Fn(&str) -> PathBuf, is_protected: &dyn Fn(&str) -> bool) -> bool {
+    let transforms = load_transforms(template_name);
+    let mut problems = Vec::new();
+    let blocked = |path: &Path| path.ancestors().skip(1).find(|ancestor| ancestor.is_file()).map(Path::to_path_buf);
+    let (mut dirs, mut created, mut overwritten, mut kept, mut needed) = (0, 0, 0, 0, 0u64);
+    for entry in entries {
+        match entry {
+            TemplateEntry::Dir(path) => {
+                let target = destination(path);
+                if target.is_file() {
+                    problems.push(format!("{}: a file is in the way of this directory", path));
+                } else if let Some(file) = blocked(&target) {
+                    problems.push(format!("{}: '{}' is a file, not a directory", path, file.display()));
+                } else if !target.exists() {
+                    dirs += 1;
+                }
+            }
+            TemplateEntry::Delete(path) => {
+                if destination(path).is_dir() {
+                    problems.push(format!("{}: it should be deleted but is a directory", path));
+                }
+            }
+            TemplateEntry::File(file) => {
+                let target = destination(&file.path);
+                let mut content = Vec::new();
+                let hash = match decode_body(file, codec, &mut content) {
+                    Ok(hash) => hash,
+                    Err(err) => {
+                        problems.push(format!("{}: the content cannot be decoded ({})", file.path, err));
+                        continue;
+                    }
+                };
+                if file.hash.as_ref().is_some_and(|expected| *expected != hash) {
+                    problems.push(format!("{}: the content hash does not match", file.path));
+                }
+                if file.size.is_some_and(|size| size != file.body.len()) {
+                    problems.push(format!("{}: the declared size does not match the encoded content", file.path));
+                }
+                if transforms.iter().any(|transform| transform.matcher.is_match(&file.path)) {
+                    content = apply_transforms(&file.path, content, file.text, &transforms);
+                }
+                if target.is_dir() {
+                    problems.push(format!("{}: a directory is in the way of this file", file.path));
+                    continue;
+                }
+                if let Some(blocking) = blocked(&target) {
+                    problems.push(format!("{}: '{}' is a file, not a directory", file.path, blocking.display()));
+                    continue;
+                }
+                if !target.exists() {
+                    created += 1;
+                } else if is_protected(&file.path) || options.conflict_policy == ConflictPolicy::SkipExisting
+                    || (options.skip_unchanged && matches_existing(&target, file, codec, &transforms)) {
+                    kept += 1;
+                    continue;
+                } else if fs::metadata(&target).is_ok_and(|metadata| metadata.permissions().readonly()) {
+                    problems.push(format!("{}: the existing file is read-only", file.path));
+                } else {
+                    overwritten += 1;
+                }
+                needed += content.len() as u64;
+            }
+        }
+    }
+    let base = destination(".");
+    let available = base.ancestors().find(|ancestor| ancestor.exists()).and_then(|existing| fs2::available_space(existing).ok());
+    if let Some(available) = available.filter(|&available| needed > available) {
+        problems.push(format!("{} bytes are needed but only {} are available", needed, available));
+    }
+    println!("Check of template '{}': {} files would be created, {} overwritten and {} left unchanged, {} directories created, {} bytes written{}.",
+        template_name, created, overwritten, kept, dirs, needed,
+        available.map(|available| format!(" ({} available)", available)).unwrap_or_default());
+    if problems.is_empty() {
+        println!("Nothing was written; applying the template should succeed.");
+        return true;
+    }
+    eprintln!("Applying the template would fail or write damaged files:");
+    for problem in &problems {
+        eprintln!("- {}", problem);
+    }
+    false
+}
+
+fn apply_template(template_name: &str, options: &ApplyOptions) -> Result<(), TemplateError> {
+    let started = Instant::now();
+    let chatty = !options.quiet && !options.json;
+    if chatty {
+        println!("Loading... Applying template '{}'.", template_name);
+    }
+    let mut timings = Timings::new(options.timings);
+    let templates_dir = options.templates_dir.clone().unwrap_or_else(get_templates_dir);
+    log::debug!("reading template '{}' from {}", template_name, templates_dir.display());
+    let template = load_template(template_name, &templates_dir)?;
+    let cipher = unlock_template(template_name, &template, options.password.as_deref(), options.keyring)
+        .map_err(|reason| TemplateError::Locked { reason })?;
+    timings.record("parse", started.elapsed());
+    log::debug!("template '{}' has format version {} and {} entries", template_name, template.format_version, template.entries.len());
+    let codec = BodyCodec { cipher: cipher.as_ref(), ..template.codec() };
+    let byte_paths = template.byte_paths();
+    if options.strip_components > 0 && chatty {
+        if let Some(root_name) = template.header_value("ROOT_NAME") {
+            println!("Not recreating the root folder '{}' of this template.", root_name);
+        }
+    }
+    let entries: Vec<TemplateEntry> = template.entries.into_iter()
+        .filter_map(|entry| match entry {
+            TemplateEntry::Dir(path) => strip_components(&path, options.strip_components).map(TemplateEntry::Dir),
+            TemplateEntry::Delete(path) => strip_components(&path, options.strip_components).map(TemplateEntry::Delete),
+            TemplateEntry::File(mut file) => strip_components(&file.path, options.strip_components).map(|path| {
+                file.path = path;
+                TemplateEntry::File(file)
+            }),
+        })
+        .collect();
+    let entries = match options.expand_env {
+        Some(mode) => {
+            let mut undefined = Vec::new();
+            let entries: Vec<TemplateEntry> = entries.into_iter()
+                .map(|entry| match entry {
+                    TemplateEntry::Dir(path) => TemplateEntry::Dir(expand_env(&path, &mut undefined)),
+                    TemplateEntry::Delete(path) => TemplateEntry::Delete(expand_env(&path, &mut undefined)),
+                    TemplateEntry::File(mut file) => {
+                        file.path = expand_env(&file.path, &mut undefined);
+                        TemplateEntry::File(file)
+                    }
+                })
+                .collect();
+            undefined.sort();
+            undefined.dedup();
+            if !undefined.is_empty() {
+                if mode == UndefinedEnv::Error {
+                    eprintln!("Template '{}' uses undefined environment variables: {}.", template_name, undefined.join(", "));
+                    eprintln!("Define them or pass --undefined-env keep to leave them in the paths as they are.");
+                    return Err(TemplateError::NotApplied);
+                }
+                eprintln!("Warning: undefined environment variables left as they are: {}.", undefined.join(", "));
+            }
+            let unsafe_paths: Vec<&str> = entries.iter()
+                .map(entry_path)
+                .filter(|path| !is_safe_expanded_path(Path::new(path), options.allow_absolute_env))
+                .collect();
+            if !unsafe_paths.is_empty() {
+                eprintln!("Template '{}' has paths that point outside the destination once environment variables are expanded:", template_name);
+                for path in &unsafe_paths {
+                    eprintln!("- {}", path);
+                }
+                eprintln!("Expanded paths can't contain '..'; pass --allow-absolute-env to write paths that become absolute.");
+                return Err(TemplateError::NotApplied);
+            }
+            entries
+        }
+        None => entries,
+    };
+    let entries: Vec<TemplateEntry> = entries.into_iter()
+        .filter(|entry| {
+            let path = entry_path(entry);
+            let env_matches = match entry {
+                TemplateEntry::File(file) => file.env.is_none() || file.env == options.env,
+                _ => true,
+            };
+            env_matches && options.only.as_ref().is_none_or(|only| only.is_match(path))
+                && !options.except.as_ref().is_some_and(|except| except.is_match(path))
+        })
+        .collect();
+    // A file tagged with the selected environment replaces the untagged entry for the same path.
+    let tagged: std::collections::HashSet<String> = entries.iter()
+        .filter_map(|entry| match entry {
+            TemplateEntry::File(file) if file.env.is_some() => Some(file.path.clone()),
+            _ => None,
+        })
+        .collect();
+    let entries: Vec<TemplateEntry> = entries.into_iter()
+        .filter(|entry| !matches!(entry, TemplateEntry::File(file) if file.env.is_none() && tagged.contains(&file.path)))
+        .collect();
+    let entries = if options.interactive {
+        match select_entries(template_name, entries) {
+            Some(entries) => entries,
+            None => return Err(TemplateError::NotApplied),
+        }
+    } else {
+        entries
+    };
+    let duplicates = duplicate_file_paths(&entries);
+    let entries = if duplicates.is_empty() {
+        entries
+    } else if options.strict {
+        eprintln!("Template '{}' has more than one entry for {}.", template_name, duplicates.join(", "));
+        eprintln!("The template is probably corrupt, nothing was written; repair it or apply it without --strict.");
+        return Err(TemplateError::NotApplied);
+    } else {
+        eprintln!("Warning: template '{}' has more than one entry for {}, the last one is used.", template_name, duplicates.join(", "));
+        keep_last_file_entries(entries)
+    };
+    let entries: Vec<TemplateEntry> = match &options.empty_dir_marker {
+        Some(marker) => entries.into_iter()
+            .map(|entry| match entry {
+                TemplateEntry::File(file) if file.body.is_empty() && Path::new(&file.path).file_name().is_some_and(|name| *name == **marker) => {
+                    match Path::new(&file.path).parent().filter(|parent| !parent.as_os_str().is_empty()) {
+                        Some(parent) => TemplateEntry::Dir(parent.to_string_lossy().to_string()),
+                        None => TemplateEntry::File(file),
+                    }
+                }
+                entry => entry,
+            })
+            .collect(),
+        None => entries,
+    };
+
+    let destination = |path: &str| {
+        let path = match byte_paths.then(|| decode_path_bytes(path)).flatten() {
+            Some(bytes) => path_from_bytes(bytes),
+            None => PathBuf::from(path),
+        };
+        let path = match &options.prefix {
+            Some(prefix) => prefix.join(path),
+            None => path,
+        };
+        match &options.output_dir {
+            Some(output_dir) => output_dir.join(path),
+            None => path,
+        }
+    };
+    let is_protected = |path: &str| options.protect.as_ref().is_some_and(|protect| protect.is_match(path));
+    if options.check {
+        return check_apply(template_name, &entries, codec, options, &destination, &is_protected)
+            .then_some(()).ok_or(TemplateError::NotApplied);
+    }
+    if let Some(output_dir) = &options.output_dir {
+        if let Err(err) = fs::create_dir_all(output_dir) {
+            eprintln!("Could not create the output directory '{}': {}", output_dir.display(), err);
+            return Err(TemplateError::NotApplied);
+        }
+    }
+    if options.dirs_only {
+        let mut dirs: Vec<PathBuf> = entries.iter()
+            .filter_map(|entry| match entry {
+                TemplateEntry::Dir(path) => Some(destination(path)),
+                TemplateEntry::File(file) => destination(&file.path).parent().map(Path::to_path_buf),
+                TemplateEntry::Delete(_) => None,
+            })
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .collect();
+        dirs.sort();
+        dirs.dedup();
+        let mut created = 0;
+        for dir in &dirs {
+            let missing = dir.ancestors().take_while(|ancestor| !ancestor.as_os_str().is_empty() && !ancestor.exists()).count();
+            if let Err(err) = fs::create_dir_all(dir) {
+                eprintln!("Warning: could not create directory '{}': {}", dir.display(), err);
+                continue;
+            }
+            created += missing;
+        }
+        if chatty {
+            println!("Created {} directories (no files were written).", created);
+        }
+        return Ok(());
+    }
+    let transforms = load_transforms(template_name);
+    if let Some(report) = &options.conflict_report {
+        let conflicts: Vec<PathBuf> = entries.iter()
+            .filter_map(|entry| match entry {
+                TemplateEntry::File(file) => Some((file, destination(&file.path))),
+                _ => None,
+            })
+            .filter(|(file, path)| path.is_file() && options.conflict_policy != ConflictPolicy::SkipExisting && !is_protected(&file.path)
+                && !(options.skip_unchanged && matches_existing(path, file, codec, &transforms)))
+            .map(|(_, path)| path)
+            .collect();
+        let contents: String = conflicts.iter().map(|path| format!("{}\n", path.display())).collect();
+        if let Err(err) = fs::write(report, contents) {
+            eprintln!("Cannot write the conflict report '{}': {}", report.display(), err);
+            return Err(TemplateError::NotApplied);
+        }
+        if !conflicts.is_empty() {
+            if !options.overwrite_confirmed {
+                eprintln!("{} existing files would be overwritten, they are listed in '{}'. Nothing was written; pass --overwrite to apply anyway.",
+                    conflicts.len(), report.display());
+                return Err(TemplateError::NotApplied);
+            }
+            if chatty {
+                println!("Overwriting {} existing files, listed in '{}'.", conflicts.len(), report.display());
+            }
+        }
+    }
+    let mut file_entries = Vec::new();
+    let mut deleted = Vec::new();
+    let mut protected_deletions = Vec::new();
+    let mut errors = Vec::new();
+    for entry in &entries {
+        match entry {
+            TemplateEntry::Dir(dir_name) => {
+                log::debug!("creating directory {}", destination(dir_name).display());
+                if let Err(err) = fs::create_dir_all(destination(dir_name)) {
+                    errors.push(format!("{}: {}", dir_name, err));
+                    if !options.keep_going {
+                        report_errors(&errors, false);
+                        return Err(TemplateError::NotApplied);
+                    }
+                }
+            }
+            TemplateEntry::File(file) => file_entries.push(&**file),
+            TemplateEntry::Delete(path) => {
+                let target = destination(path);
+                if is_protected(path) && target.exists() {
+                    protected_deletions.push(target);
+                    continue;
+                }
+                match fs::symlink_metadata(&target) {
+                    Ok(metadata) if metadata.is_dir() => {
+                        eprintln!("Warning: not deleting '{}', it is a directory.", target.display());
+                    }
+                    Ok(_) => match fs::remove_file(&target) {
+                        Ok(()) => deleted.push(target),
+                        Err(err) => eprintln!("Warning: could not delete '{}': {}", target.display(), err),
+                    },
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+    if !deleted.is_empty() && chatty {
+        println!("Deleted {} files:", deleted.len());
+        for path in &deleted {
+            println!("- {}", path.display());
+        }
+    }
+
+    let pb = new_progress_bar(file_entries.len() as u64, !chatty || options.no_progress);
+    let owner_failures = AtomicUsize::new(0);
+    let xattr_unsupported = AtomicUsize::new(0);
+    let merged = AtomicUsize::new(0);
+    let decode_nanos = AtomicU64::new(0);
+    let write_nanos = AtomicU64::new(0);
+
+    let backup_run = options.backup_dir.as_deref().map(|dir| backup_run_dir(dir, template_name));
+    let stop = AtomicBool::new(false);
+    let apply_file = |file: &TemplateFile| -> Result<FileOutcome, String> {
+        let file_name = &file.path;
+        let fail = |err: std::io::Error| format!("{}: {}", file_name, err);
+        // Errors of the template content itself (not of the file being written) name the entry of the template.
+        let fail_decoding = |err: std::io::Error| match err.kind() {
+            std::io::ErrorKind::InvalidData | std::io::ErrorKind::UnexpectedEof =>
+                TemplateError::DamagedContent { path: file_name.clone(), line: file.line, reason: err.to_string() }.to_string(),
+            _ => fail(err),
+        };
+        let path = &destination(file_name);
+        let exists = path.exists();
+        if exists && is_protected(file_name) {
+            log::debug!("not touching protected file {}", path.display());
+            return Ok(FileOutcome::Protected(path.clone()));
+        }
+        if exists && options.conflict_policy == ConflictPolicy::SkipExisting {
+            log::debug!("skipping existing file {}", path.display());
+            return Ok(FileOutcome::Skipped);
+        }
+        if exists && options.skip_unchanged && matches_existing(path, file, codec, &transforms) {
+            log::debug!("skipping unchanged file {}", path.display());
+            return Ok(FileOutcome::Unchanged);
+        }
+        log::debug!("writing {}{}", path.display(), if exists { " (overwriting)" } else { "" });
+
+        if file.size.is_some_and(|size| size != file.body.len()) {
+            eprintln!("Warning: the declared size does not match the encoded content for file {}", file_name);
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(fail)?;
+        }
+
+        let transformed = transforms.iter().any(|transform| transform.matcher.is_match(file_name));
+        let merge = merge_format(file_name, options).filter(|_| exists);
+        let decoded = if transformed || merge.is_some() || options.timings {
+            let decode_started = Instant::now();
+            let mut content = Vec::new();
+            let hash = decode_body(file, codec, &mut content).map_err(fail_decoding)?;
+            if transformed {
+                content = apply_transforms(file_name, content, file.text, &transforms);
+            }
+            if let Some(format) = merge {
+                match merge_structured(format, &fs::read(path).unwrap_or_default(), &content, options.merge_prefer) {
+                    Ok(merged_content) => {
+                        content = merged_content;
+                        merged.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(err) => {
+                        eprintln!("Warning: could not merge {}: {}; the existing file was left unchanged.", file_name, err);
+                        return Ok(FileOutcome::Skipped);
+                    }
+                }
+            }
+            decode_nanos.fetch_add(decode_started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+            Some((content, hash))
+        } else {
+            None
+        };
+
+        let mut outcome = if exists { FileOutcome::Written } else { FileOutcome::Created(path.clone()) };
+        if exists && options.backup {
+            let backup = backup_path(path);
+            fs::copy(path, &backup).map_err(fail)?;
+            outcome = FileOutcome::BackedUp(backup);
+        }
+        if let Some(backup_run) = backup_run.as_deref().filter(|_| exists) {
+            let backup = backup_run.join(file_name);
+            fs::create_dir_all(backup.parent().unwrap_or(backup_run))
+                .and_then(|_| fs::copy(path, &backup))
+                .map_err(fail)?;
+            outcome = FileOutcome::BackedUp(backup);
+        }
+        // The content goes to a file next to the target, which replaces it only once it is complete and its hash
+        // matches, so damaged content never leaves an existing file truncated or half-written.
+        let target = match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => fs::canonicalize(path).map_err(fail)?,
+            _ => path.clone(),
+        };
+        let partial = partial_path(&target);
+        let write_partial = || -> Result<(), String> {
+            let mut writer = std::io::BufWriter::new(File::create(&partial).map_err(fail)?);
+            let hash = match decoded {
+                Some((content, hash)) => {
+                    let write_started = Instant::now();
+                    writer.write_all(&content).map_err(fail)?;
+                    writer.flush().map_err(fail)?;
+                    write_nanos.fetch_add(write_started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+                    hash
+                }
+                None => decode_body(file, codec, &mut writer).map_err(fail_decoding)?,
+            };
+            writer.into_inner().map_err(|err| fail(err.into_error()))?;
+            if file.hash.as_ref().is_some_and(|expected| *expected != hash) {
+                let reason = "it does not match its HASH".to_string();
+                return Err(TemplateError::DamagedContent { path: file_name.clone(), line: file.line, reason }.to_string());
+            }
+            if exists {
+                let permissions = fs::metadata(&target).map_err(fail)?.permissions();
+                fs::set_permissions(&partial, permissions).map_err(fail)?;
+            }
+            fs::rename(&partial, &target).map_err(fail)
+        };
+        if let Err(err) = write_partial() {
+            let _ = fs::remove_file(&partial);
+            return Err(err);
+        }
+        if let Some(owner) = file.owner.map(|owner| map_owner(owner, options.owner_map.as_deref())) {
+            if let Err(err) = restore_owner(path, owner) {
+                if err.kind() == std::io::ErrorKind::PermissionDenied {
+                    owner_failures.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    eprintln!("Warning: could not restore the owner of {}: {}", file_name, err);
+                }
+            }
+        }
+        for (name, value) in &file.xattrs {
+            if let Err(err) = restore_xattr(path, name, value) {
+                if err.kind() == std::io::ErrorKind::Unsupported {
+                    xattr_unsupported.fetch_add(1, Ordering::Relaxed);
+                    break;
+                }
+                eprintln!("Warning: could not restore the extended attribute {} of {}: {}", name, file_name, err);
+            }
+        }
+        if let Err(err) = apply_file_mode(path, options.chmod, options.umask) {
+            eprintln!("Warning: could not set the permissions of {}: {}", file_name, err);
+        }
+        Ok(outcome)
+    };
+    let outcomes: Vec<FileOutcome> = file_entries.par_iter().map(|file| {
+        if interrupted() || stop.load(Ordering::Relaxed) {
+            return FileOutcome::Interrupted;
+        }
+        let outcome = apply_file(file).unwrap_or_else(|err| {
+            if !options.keep_going {
+                stop.store(true, Ordering::Relaxed);
+            }
+            FileOutcome::Failed(err)
+        });
+        pb.inc(1);
+        outcome
+    }).collect();
+    if interrupted() {
+        pb.abandon();
+        let created: Vec<&PathBuf> = outcomes.iter()
+            .filter_map(|outcome| match outcome {
+                FileOutcome::Created(path) => Some(path),
+                _ => None,
+            })
+            .collect();
+        for path in &created {
+            let _ = fs::remove_file(path);
+        }
+        eprintln!("Removed the {} files created before the interruption.", created.len());
+        let overwritten = outcomes.iter()
+            .filter(|outcome| matches!(outcome, FileOutcome::Written | FileOutcome::BackedUp(_)))
+            .count();
+        if overwritten > 0 {
+            eprintln!("{} existing files had already been overwritten and keep their new content.", overwritten);
+        }
+        std::process::exit(INTERRUPTED_EXIT_CODE);
+    }
+    pb.finish_with_message("Template applied successfully");
+    timings.record("decode (all threads)", Duration::from_nanos(decode_nanos.into_inner()));
+    timings.record("write (all threads)", Duration::from_nanos(write_nanos.into_inner()));
+    timings.record("total", started.elapsed());
+    timings.report("get");
+
+    let xattr_unsupported = xattr_unsupported.into_inner();
+    if xattr_unsupported > 0 {
+        eprintln!("Warning: extended attributes are not supported here, they were skipped for {} files.", xattr_unsupported);
+    }
+    let owner_failures = owner_failures.into_inner();
+    if owner_failures > 0 {
+        eprintln!("Warning: not enough privileges to restore the owner of {} files, their ownership was left unchanged.", owner_failures);
+    }
+    let skipped = outcomes.iter().filter(|outcome| matches!(outcome, FileOutcome::Skipped)).count();
+    if skipped > 0 && chatty {
+        println!("Skipped {} existing files.", skipped);
+    }
+    let protected: Vec<&PathBuf> = outcomes.iter()
+        .filter_map(|outcome| match outcome {
+            FileOutcome::Protected(path) => Some(path),
+            _ => None,
+        })
+        .chain(&protected_deletions)
+        .collect();
+    if !protected.is_empty() && chatty {
+        println!("Left {} protected files untouched:", protected.len());
+        for path in protected {
+            println!("- {}", path.display());
+        }
+    }
+    let merged = merged.into_inner();
+    if merged > 0 && chatty {
+        println!("Merged {} existing JSON/TOML files with the template.", merged);
+    }
+    let unchanged = outcomes.iter().filter(|outcome| matches!(outcome, FileOutcome::Unchanged)).count();
+    if options.skip_unchanged && chatty {
+        println!("Skipped {} files that were already up to date.", unchanged);
+    }
+    let backups: Vec<&PathBuf> = outcomes.iter()
+        .filter_map(|outcome| match outcome {
+            FileOutcome::BackedUp(backup) => Some(backup),
+            _ => None,
+        })
+        .collect();
+    if let Some(backup_run) = backup_run.filter(|_| !backups.is_empty()) {
+        if chatty {
+            println!("Backed up {} overwritten files to '{}'.", backups.len(), backup_run.display());
+        }
+    } else if !backups.is_empty() && chatty {
+        println!("Backed up {} overwritten files:", backups.len());
+        for backup in backups {
+            println!("- {}", backup.display());
+        }
+    }
+
+    let written: Vec<&&TemplateFile> = file_entries.iter().zip(&outcomes)
+        .filter(|(_, outcome)| !matches!(outcome, FileOutcome::Skipped | FileOutcome::Protected(_) | FileOutcome::Unchanged | FileOutcome::Interrupted | FileOutcome::Failed(_)))
+        .map(|(file, _)| file)
+        .collect();
+    if options.git_add {
+        let paths: Vec<PathBuf> = written.iter().map(|file| destination(&file.path)).chain(deleted.iter().cloned()).collect();
+        stage_in_git(options.output_dir.as_deref(), &paths, chatty);
+    }
+    let written_tree = (options.print_tree_after && chatty).then(|| {
+        let mut tree = TreeNode::default();
+        for entry in &entries {
+            if let TemplateEntry::Dir(path) = entry {
+                insert_tree_path(&mut tree, &destination(path), TreeLeaf::Dir);
+            }
+        }
+        for file in &written {
+            insert_tree_path(&mut tree, &destination(&file.path), TreeLeaf::File(file.content_len(codec)));
+        }
+        for path in &deleted {
+            insert_tree_path(&mut tree, path, TreeLeaf::Deleted);
+        }
+        tree
+    });
+    if !options.quiet {
+        let summary = OperationSummary {
+            files: written.len(),
+            directories: count_directories(&entries),
+            bytes: written.iter().filter_map(|file| file.content_len(codec)).sum(),
+            elapsed: started.elapsed(),
+            template_bytes: None,
+        };
+        print_summary("get", template_name, &summary, options.json);
+    }
+    if let Some(profile) = &options.profile_json {
+        let files: Vec<(&str, u64)> = written.iter().map(|file| (file.path.as_str(), file.content_len(codec).unwrap_or_default())).collect();
+        write_profile(profile, "get", template_name, &timings, &files, started.elapsed());
+    }
+
+    errors.extend(outcomes.into_iter().filter_map(|outcome| match outcome {
+        FileOutcome::Failed(err) => Some(err),
+        _ => None,
+    }));
+    if !errors.is_empty() {
+        report_errors(&errors, options.keep_going);
+        return Err(TemplateError::NotApplied);
+    }
+    if let Some(tree) = written_tree {
+        println!("Written by '{}':", template_name);
+        print_tree(&tree, 1);
+    }
+    if options.update_lockfile {
+        record_in_lockfile(template_name, options);
+    }
+    Ok(())
+}
+
+fn report_errors(errors: &[String], keep_going: bool) {
+    eprintln!("{} errors:", errors.len());
+    for err in errors {
+        eprintln!("- {}", err);
+    }
+    if !keep_going {
+        eprintln!("Stopped at the first error; pass --keep-going to process everything else and report all errors.");
+    }
+}
+
+#[derive(Default)]
+enum TreeLeaf {
+    #[default]
+    Dir,
+    // The size of the content, when the template records it.
+    File(Option<u64>),
+    Deleted,
+}
+
+#[derive(Default)]
+struct TreeNode {
+    children: std::collections::BTreeMap<String, TreeNode>,
+    leaf: TreeLeaf,
+}
+
+fn build_tree(entries: &[TemplateEntry], codec: BodyCodec) -> TreeNode {
+    let mut root = TreeNode::default();
+    for entry in entries {
+        let (path, leaf) = match entry {
+            TemplateEntry::Dir(path) => (path, TreeLeaf::Dir),
+            TemplateEntry::Delete(path) => (path, TreeLeaf::Deleted),
+            TemplateEntry::File(file) => (&file.path, TreeLeaf::File(file.content_len(codec))),
+        };
+        insert_tree_path(&mut root, Path::new(path), leaf);
+    }
+    root
+}
+
+fn insert_tree_path(root: &mut TreeNode, path: &Path, leaf: TreeLeaf) {
+    let mut node = root;
+    for component in path.components() {
+        node = node.children
+            .entry(component.as_os_str().to_string_lossy().to_string())
+            .or_default();
+    }
+    node.leaf = leaf;
+}
+
+fn print_tree(node: &TreeNode, depth: usize) {
+    for (name, child) in &node.children {
+        let indent = "  ".repeat(depth);
+        match child.leaf {
+            TreeLeaf::Deleted => println!("{}{} (deleted)", indent, name),
+            TreeLeaf::File(Some(size)) if child.children.is_empty() => println!("{}{} ({} bytes)", indent, name, size),
+            TreeLeaf::File(None) if child.children.is_empty() => println!("{}{}", indent, name),
+            _ => println!("{}{}/", indent, name),
+        }
+        print_tree(child, depth + 1);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Tree,
+    Flat,
+    Paths,
+}
+
+fn parse_output_format(args: &[String], default: OutputFormat) -> OutputFormat {
+    match flag_values(args, "--output-format").pop().as_deref() {
+        None => default,
+        Some("tree") => OutputFormat::Tree,
+        Some("flat") => OutputFormat::Flat,
+        Some("paths") => OutputFormat::Paths,
+        Some(other) => {
+            println!("Invalid value for --output-format: '{}' (expected tree, flat or paths).", other);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn show_template_tree(template_name: &str, templates_dir: &Path, format: OutputFormat) -> bool {
+    let Some(template) = read_template(template_name, templates_dir) else {
+        return false;
+    };
+    let mut paths: Vec<String> = template.entries.iter()
+        .map(|entry| match entry {
+            TemplateEntry::Dir(path) => format!("{}/", path),
+            TemplateEntry::File(file) => match &file.env {
+                Some(env) if format == OutputFormat::Flat => format!("{} [env={}]", file.path, env),
+                _ => file.path.clone(),
+            },
+            TemplateEntry::Delete(path) => format!("{} (deleted)", path),
+        })
+        .collect();
+    paths.sort();
+    match format {
+        OutputFormat::Tree => {
+            println!("{}", template_name);
+            print_tree(&build_tree(&template.entries, template.codec()), 1);
+        }
+        OutputFormat::Flat => {
+            println!("{}:", template_name);
+            for path in paths {
+                println!("- {}", path);
+            }
+        }
+        OutputFormat::Paths => {
+            for path in paths {
+                println!("{}", path);
+            }
+        }
+    }
+    true
+}
+
+fn repair_template(template_name: &str) {
+    let Some(mut template) = read_template(template_name, &get_templates_dir()) else {
+        return;
+    };
+    if template.encrypted() {
+        println!("Template '{}' is encrypted, its files are authenticated by the encryption and cannot be repaired.", template_name);
+        println!("Use `tdmcli verify {} --deep` to check it.", template_name);
+        return;
+    }
+
+    let mut fixed = 0;
+    let codec = template.codec();
+    for entry in &mut template.entries {
+        let TemplateEntry::File(file) = entry else {
+            continue;
+        };
+        let hash = match decode_body(file, codec, &mut std::io::sink()) {
+            Ok(hash) => hash,
+            Err(err) => {
+                eprintln!("Cannot repair '{}': its content cannot be decoded ({}).", file.path, err);
+                continue;
+            }
+        };
+        let size = file.body.len();
+
+        let mut changes = Vec::new();
+        match file.size {
+            Some(old) if old == size => {}
+            Some(old) => changes.push(format!("SIZE {} -> {}", old, size)),
+            None => changes.push(format!("added SIZE {}", size)),
+        }
+        match &file.hash {
+            Some(old) if *old == hash => {}
+            Some(_) => changes.push("corrected HASH".to_string()),
+            None => changes.push("added HASH".to_string()),
+        }
+        if !changes.is_empty() {
+            println!("Fixed '{}': {}", file.path, changes.join(", "));
+            file.size = Some(size);
+            file.hash = Some(hash);
+            fixed += 1;
+        }
+    }
+
+    if fixed == 0 && template.format_version == FORMAT_VERSION {
+        println!("Template '{}' is already consistent, nothing to repair.", template_name);
+        return;
+    }
+
+    let template_path = template_file_path(&get_templates_dir(), template_name);
+    template.format_version = FORMAT_VERSION;
+    if let Err(err) = replace_template_file(&template_path, &mut template) {
+        eprintln!("Cannot save the repaired template '{}': {}.", template_name, err);
+        std::process::exit(1);
+    }
+    println!("Template '{}' repaired: {} entries fixed.", template_name, fixed);
+}
+
+// Rewrites a template in the current format version. Returns the version it was upgraded from,
+// or None when it already is in the current format.
+fn migrate_template(templates_dir: &Path, template_name: &str, backup: bool) -> Result<Option<u32>, String> {
+    let template_path = template_file_path(templates_dir, template_name);
+    let content = read_template_bytes(&template_path).map_err(|err| err.to_string())?;
+    let mut template = parse_template_bytes(&content).map_err(|err| err.to_string())?;
+    let old_version = template.format_version;
+    if old_version == FORMAT_VERSION {
+        return Ok(None);
+    }
+    let codec = template.codec();
+    for entry in &mut template.entries {
+        if let TemplateEntry::File(file) = entry {
+            let hash = decode_body(file, codec, &mut std::io::sink())
+                .map_err(|_| format!("the content of '{}' is not valid {}", file.path, codec.encoding.name()))?;
+            file.size = Some(file.body.len());
+            file.hash = Some(hash);
+        }
+    }
+    if backup {
+        for file in template_files(&template_path) {
+            let mut backup = file.as_os_str().to_owned();
+            backup.push(format!(".v{}.bak", old_version));
+            fs::copy(&file, &backup).map_err(|err| format!("could not back up '{}': {}", file.display(), err))?;
+        }
+    }
+    template.format_version = FORMAT_VERSION;
+    replace_template_file(&template_path, &mut template).map_err(|err| format!("could not write the template: {}", err))?;
+    Ok(Some(old_version))
+}
+
+fn migrate_templates(names: &[String], backup: bool) {
+    let templates_dir = get_templates_dir();
+    let (mut migrated, mut current, mut failed) = (0, 0, 0);
+    for name in names {
+        if !template_file_path(&templates_dir, name).exists() {
+            println!("Template '{}' not found.", name);
+            failed += 1;
+            continue;
+        }
+        match migrate_template(&templates_dir, name, backup) {
+            Ok(Some(old_version)) => {
+                println!("Migrated '{}' from format version {} to {}.", name, old_version, FORMAT_VERSION);
+                migrated += 1;
+            }
+            Ok(None) => current += 1,
+            Err(err) => {
+                eprintln!("Cannot migrate '{}': {}.", name, err);
+                failed += 1;
+            }
+        }
+    }
+    println!("{} templates migrated, {} already in format version {}{}.", migrated, current, FORMAT_VERSION,
+        if failed > 0 { format!(", {} failed", failed) } else { String::new() });
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
+
+fn verify_template(template_name: &str, templates_dir: &Path, deep: bool, password: Option<&str>, keyring: bool) -> bool {
+    let Some(template) = read_template(template_name, templates_dir) else {
+        return false;
+    };
+    let files: Vec<&TemplateFile> = template.entries.iter()
+        .filter_map(|entry| match entry {
+            TemplateEntry::File(file) => Some(&**file),
+            _ => None,
+        })
+        .collect();
+    if let Some(file) = files.iter().find(|file| file.size.is_some_and(|size| size != file.body.len())) {
+        eprintln!("Template '{}' is damaged: the declared size does not match the encoded content of '{}'.", template_name, file.path);
+        return false;
+    }
+    let duplicates = duplicate_file_paths(&template.entries);
+    if !duplicates.is_empty() {
+        eprintln!("Warning: template '{}' has more than one entry for {}, the last one is used.", template_name, duplicates.join(", "));
+    }
+    if !deep {
+        println!("Template '{}' is well-formed: {} files, {} directories (use --deep to also check the file contents).",
+            template_name, files.len(), count_directories(&template.entries));
+        return true;
+    }
+    let cipher = match unlock_template(template_name, &template, password, keyring) {
+        Ok(cipher) => cipher,
+        Err(err) => {
+            eprintln!("Cannot decrypt template '{}': {}.", template_name, err);
+            return false;
+        }
+    };
+    let codec = BodyCodec { cipher: cipher.as_ref(), ..template.codec() };
+    let checked: Vec<Result<u64, String>> = files.par_iter()
+        .map(|file| {
+            let mut counter = ByteCounter::default();
+            let hash = decode_body(file, codec, &mut counter)
+                .map_err(|err| format!("the content of '{}' cannot be decoded ({})", file.path, err))?;
+            if file.hash.as_ref().is_some_and(|expected| *expected != hash) {
+                return Err(format!("the content hash of '{}' does not match", file.path));
+            }
+            if file.length.is_some_and(|length| length != counter.0) {
+                return Err(format!("the content of '{}' is shorter than its LENGTH", file.path));
+            }
+            Ok(counter.0)
+        })
+        .collect();
+    let mut bytes = 0u64;
+    for result in checked {
+        match result {
+            Ok(len) => bytes += len,
+            Err(err) => {
+                eprintln!("Template '{}' is damaged: {}.", template_name, err);
+                return false;
+            }
+        }
+    }
+    // Encrypted files are authenticated by their cipher instead.
+    let unhashed = if cipher.is_some() { 0 } else { files.iter().filter(|file| file.hash.is_none()).count() };
+    println!("Template '{}' is valid: {} files ({} bytes) decoded and checked, {} directories.",
+        template_name, files.len(), bytes, count_directories(&template.entries));
+    if unhashed > 0 {
+        println!("{} files have no HASH and could only be decoded; `tdmcli repair {}` adds it.", unhashed, template_name);
+    }
+    true
+}
+
+#[derive(Default)]
+struct ByteCounter(u64);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn touch_template(template_name: &str) {
+    let template_path = template_file_path(&get_templates_dir(), template_name);
+    let files = template_files(&template_path);
+    if files.is_empty() {
+        println!("Template '{}' not found.", template_name);
+        std::process::exit(1);
+    }
+    let now = std::time::SystemTime::now();
+    for file in &files {
+        if let Err(err) = File::options().write(true).open(file).and_then(|file| file.set_modified(now)) {
+            eprintln!("Cannot touch '{}': {}.", file.display(), err);
+            std::process::exit(1);
+        }
+    }
+    let summary_path = template_summary_path(&template_path);
+    if let Some(mut summary) = fs::read_to_string(&summary_path).ok()
+        .and_then(|content| serde_json::from_str::<serde_json::Value>(&content).ok())
+        .filter(serde_json::Value::is_object)
+    {
+        let touched = now.duration_since(std::time::UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0);
+        summary["touched_unix"] = touched.into();
+        if let Err(err) = fs::write(&summary_path, serde_json::to_string_pretty(&summary).unwrap() + "\n") {
+            eprintln!("Warning: could not update the summary '{}': {}", summary_path.display(), err);
+        }
+    }
+    println!("Template '{}' touched.", template_name);
+}
+
+fn rewrite_prefix(path: &str, old_prefix: &str, new_prefix: &str) -> Option<String> {
+    if path == old_prefix {
+        return Some(new_prefix.to_string());
+    }
+    let rest = path.strip_prefix(old_prefix)?.strip_prefix(['/', '\\'])?;
+    Some(format!("{}/{}", new_prefix, rest))
+}
+
+fn move_in_template(template_name: &str, old_prefix: &str, new_prefix: &str) {
+    let old_prefix = old_prefix.trim_end_matches(['/', '\\']);
+    let new_prefix = new_prefix.trim_end_matches(['/', '\\']);
+    if new_prefix.is_empty() || !is_safe_relative_path(Path::new(new_prefix)) {
+        println!("Invalid new path '{}': it must be a relative path without '..'.", new_prefix);
+        std::process::exit(1);
+    }
+    let templates_dir = get_templates_dir();
+    let Some(mut template) = read_template(template_name, &templates_dir) else {
+        std::process::exit(1);
+    };
+
+    let mut changed = 0;
+    for entry in &mut template.entries {
+        let path = match entry {
+            TemplateEntry::Dir(path) | TemplateEntry::Delete(path) => path,
+            TemplateEntry::File(file) => &mut file.path,
+        };
+        if let Some(renamed) = rewrite_prefix(path, old_prefix, new_prefix) {
+            *path = renamed;
+            changed += 1;
+        }
+    }
+    if changed == 0 {
+        println!("No entry of template '{}' is at or under '{}', nothing changed.", template_name, old_prefix);
+        return;
+    }
+
+    let template_path = template_file_path(&templates_dir, template_name);
+    if let Err(err) = replace_template_file(&template_path, &mut template) {
+        eprintln!("Cannot save the template '{}': {}.", template_name, err);
+        std::process::exit(1);
+    }
+    println!("Template '{}' updated: {} entries moved from '{}' to '{}'.", template_name, changed, old_prefix, new_prefix);
+}
+
+fn remove_template(templates_dir: &Path, template_name: &str) -> bool {
+    let template_path = template_file_path(templates_dir, template_name);
+    let files = template_files(&template_path);
+    if files.is_empty() || !files.iter().all(|file| fs::remove_file(file).is_ok()) {
+        return false;
+    }
+    let _ = fs::remove_file(template_path.with_file_name(gzip_file_name(template_name)));
+    let _ = fs::remove_file(template_summary_path(&template_path));
+    prune_blobs(templates_dir);
+    true
+}
+
+fn delete_template(template_name: &str) {
+    let templates_dir = get_templates_dir();
+    let exists = !template_files(&template_file_path(&templates_dir, template_name)).is_empty();
+    if exists && !confirm(&format!("Delete template '{}'?", template_name)) {
+        println!("Template '{}' was not deleted.", template_name);
+        std::process::exit(1);
+    }
+    if remove_template(&templates_dir, template_name) {
+        println!("Template '{}' deleted successfully.", template_name);
+    } else {
+        println!("Template '{}' not found.", template_name);
+    }
+}
+
+fn show_template_path(template_name: &str, templates_dir: &Path) -> bool {
+    check_template_name(template_name);
+    let path = template_file_path(templates_dir, template_name);
+    if !path.is_file() {
+        eprintln!("Template '{}' not found.", template_name);
+        return false;
+    }
+    println!("{}", fs::canonicalize(&path).unwrap_or(path).display());
+    true
+}
+
+fn show_template_directory() {
+    let dir = resolve_templates_dir();
+    println!("Templates directory: {}", dir.display());
+}
+
+fn template_names(templates_dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(templates_dir)
+        .unwrap_or_else(|err| templates_dir_error(templates_dir, &err))
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().to_str()
+            .and_then(parse_template_file_name)
+            .map(|(name, _)| name.to_string()))
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+struct TemplateStat {
+    name: String,
+    bytes: u64,
+    modified_unix: Option<u64>,
+    files: Option<usize>,
+}
+
+fn describe_age(modified_unix: Option<u64>) -> String {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    match modified_unix.map(|modified| now.saturating_sub(modified) / 86_400) {
+        None => "modification time unknown".to_string(),
+        Some(0) => "modified today".to_string(),
+        Some(1) => "modified 1 day ago".to_string(),
+        Some(days) => format!("modified {} days ago", days),
+    }
+}
+
+fn show_store_stats(count_files: bool, json: bool) {
+    let templates_dir = get_templates_dir();
+    let mut stats: Vec<TemplateStat> = template_names(&templates_dir).into_iter()
+        .map(|name| {
+            let path = template_file_path(&templates_dir, &name);
+            let metadata = fs::metadata(&path).ok();
+            let bytes = template_files(&path).iter()
+                .filter_map(|file| fs::metadata(file).ok())
+                .map(|metadata| metadata.len())
+                .sum();
+            let modified_unix = metadata.as_ref()
+                .and_then(|metadata| metadata.modified().ok())
+                .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|elapsed| elapsed.as_secs());
+            let files = count_files.then(|| read_template_bytes(&path).ok()
+                .and_then(|content| parse_template_bytes(&content).ok())
+                .map(|template| template.entries.iter().filter(|entry| matches!(entry, TemplateEntry::File(_))).count()))
+                .flatten();
+            TemplateStat { name, bytes, modified_unix, files }
+        })
+        .collect();
+    stats.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.name.cmp(&b.name)));
+    let total_bytes: u64 = stats.iter().map(|stat| stat.bytes).sum();
+    let oldest = stats.iter().filter(|stat| stat.modified_unix.is_some()).min_by_key(|stat| stat.modified_unix);
+    let newest = stats.iter().filter(|stat| stat.modified_unix.is_some()).max_by_key(|stat| stat.modified_unix);
+
+    if json {
+        let templates: Vec<serde_json::Value> = stats.iter()
+            .map(|stat| {
+                let mut value = serde_json::json!({
+                    "name": stat.name,
+                    "bytes": stat.bytes,
+                    "modified_unix": stat.modified_unix,
+                });
+                if count_files {
+                    value["files"] = serde_json::json!(stat.files);
+                }
+                value
+            })
+            .collect();
+        let summary = serde_json::json!({
+            "templates_dir": templates_dir.to_string_lossy(),
+            "templates": stats.len(),
+            "total_bytes": total_bytes,
+            "oldest": oldest.map(|stat| &stat.name),
+            "newest": newest.map(|stat| &stat.name),
+            "entries": templates,
+        });
+        println!("{}", summary);
+        return;
+    }
+
+    println!("Templates directory: {}", templates_dir.display());
+    println!("{} templates, {} bytes in total.", stats.len(), total_bytes);
+    if stats.is_empty() {
+        return;
+    }
+    println!("Largest templates:");
+    for stat in stats.iter().take(STATS_LARGEST_COUNT) {
+        match stat.files {
+            Some(files) => println!("- {} ({} bytes, {} files)", stat.name, stat.bytes, files),
+            None => println!("- {} ({} bytes)", stat.name, stat.bytes),
+        }
+    }
+    if let (Some(oldest), Some(newest)) = (oldest, newest) {
+        println!("Oldest: {} ({})", oldest.name, describe_age(oldest.modified_unix));
+        println!("Newest: {} ({})", newest.name, describe_age(newest.modified_unix));
+    }
+}
+
+fn template_description(templates_dir: &Path, template_name: &str) -> Option<String> {
+    let content = read_template_bytes(&template_file_path(templates_dir, template_name)).ok()?;
+    parse_template_bytes(&content).ok()?.header_value("DESCRIPTION").map(str::to_string)
+}
+
+// Compares a template with the directory it was made from (its SOURCE header): a file is out of date when it
+// is missing there, has another size or was modified after the template. Only the files of the template are checked.
+fn template_staleness(templates_dir: &Path, template_name: &str) -> String {
+    let template_path = template_file_path(templates_dir, template_name);
+    let Some(template) = read_template_bytes(&template_path).ok().and_then(|content| parse_template_bytes(&content).ok()) else {
+        return "unreadable".to_string();
+    };
+    let Some(source) = template.header_value("SOURCE").map(PathBuf::from) else {
+        return "no source recorded".to_string();
+    };
+    if !source.is_dir() {
+        return format!("orphaned, '{}' no longer exists", source.display());
+    }
+    let created = fs::metadata(&template_path).and_then(|metadata| metadata.modified()).ok();
+    let codec = template.codec();
+    let files: Vec<&TemplateFile> = template.entries.iter()
+        .filter_map(|entry| match entry {
+            TemplateEntry::File(file) => Some(&**file),
+            _ => None,
+        })
+        .collect();
+    let changed = files.iter()
+        .filter(|file| {
+            let path = match decode_path_bytes(&file.path).filter(|_| template.byte_paths()) {
+                Some(bytes) => path_from_bytes(bytes),
+                None => PathBuf::from(&file.path),
+            };
+            let path: PathBuf = path.components().skip(usize::from(template.header_value("ROOT_NAME").is_some())).collect();
+            let Ok(metadata) = fs::metadata(source.join(path)) else {
+                return true;
+            };
+            // Compressed files without a LENGTH are only compared by modification time.
+            let size_changed = file.blob.is_none() && !file.body.is_empty()
+                && file.content_len(codec).is_some_and(|len| metadata.len() != len);
+            size_changed || metadata.modified().ok().zip(created).is_some_and(|(modified, created)| modified > created)
+        })
+        .count();
+    if changed > 0 {
+        format!("stale, {} of {} files changed or missing in '{}'", changed, files.len(), source.display())
+    } else {
+        "up to date".to_string()
+    }
+}
+
+fn list_templates(templates_dir: Option<&Path>, format: OutputFormat, verbose: bool, stale: bool) {
+    let templates_dir = match templates_dir {
+        Some(dir) if !dir.is_dir() => {
+            eprintln!("Directory '{}' does not exist.", dir.display());
+            std::process::exit(1);
+        }
+        Some(dir) => dir.to_path_buf(),
+        None => get_templates_dir(),
+    };
+    let templates = template_names(&templates_dir);
+
+    if format == OutputFormat::Paths {
+        for template in templates {
+            let path = template_file_path(&templates_dir, &template);
+            println!("{}", fs::canonicalize(&path).unwrap_or(path).display());
+        }
+    } else if format == OutputFormat::Tree {
+        for template in templates {
+            show_template_tree(&template, &templates_dir, OutputFormat::Tree);
+        }
+    } else if templates.is_empty() {
+        eprintln!("No templates found.");
+    } else {
+        println!("Available templates:");
+        for template in templates {
+            let mut line = format!("- {}", template);
+            if let Some(description) = template_description(&templates_dir, &template).filter(|_| verbose) {
+                line.push_str(&format!(": {}", description));
+            }
+            if stale {
+                line.push_str(&format!(" [{}]", template_staleness(&templates_dir, &template)));
+            }
+            println!("{}", line);
+        }
+    }
+}
+
+fn show_version() {
+    println!("tdmcli version: {}", VERSION);
+}
+
+fn checksum_path(path: &Path) -> PathBuf {
+    let mut checksum = path.as_os_str().to_owned();
+    checksum.push(".sha256");
+    PathBuf::from(checksum)
+}
+
+fn write_checksum_file(path: &Path) {
+    let hash = sha256_hex(&fs::read(path).unwrap());
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let checksum = checksum_path(path);
+    fs::write(&checksum, format!("{}  {}\n", hash, file_name)).unwrap();
+    println!("Checksum written to '{:?}'", checksum);
+}
+
+fn verify_checksum_file(path: &Path) -> bool {
+    let checksum = checksum_path(path);
+    let Ok(contents) = fs::read_to_string(&checksum) else {
+        return true;
+    };
+    let expected = contents.split_whitespace().next().unwrap_or_default().to_ascii_lowercase();
+    let actual = sha256_hex(&fs::read(path).unwrap());
+    if expected != actual {
+        eprintln!("Checksum mismatch for '{:?}': expected {}, got {}.", path, expected, actual);
+        eprintln!("The file may be corrupted or incomplete, it was not imported.");
+        return false;
+    }
+    println!("Checksum verified with '{:?}'", checksum);
+    true
+}
+
+fn is_template_pattern(name: &str) -> bool {
+    name.contains(['*', '?', '[', '{'])
+}
+
+fn expand_template_patterns(names: &[String]) -> Vec<String> {
+    let mut expanded = Vec::new();
+    for name in names {
+        if !is_template_pattern(name) {
+            expanded.push(name.clone());
+            continue;
+        }
+        let matcher = match Glob::new(name) {
+            Ok(glob) => glob.compile_matcher(),
+            Err(err) => {
+                println!("Invalid template name pattern '{}' ({}).", name, err);
+                std::process::exit(1);
+            }
+        };
+        let matches: Vec<String> = template_names(&get_templates_dir()).into_iter()
+            .filter(|template_name| matcher.is_match(template_name))
+            .collect();
+        if matches.is_empty() {
+            println!("No template matches '{}'.", name);
+            std::process::exit(1);
+        }
+        expanded.extend(matches);
+    }
+    expanded
+}
+
+// A `--hash-names` template with the content of its files put back inline, for exporting it on its own.
+// None for templates that don't use blobs (or can't be read), which are exported as they are.
+fn inline_blob_template(templates_dir: &Path, template_path: &Path) -> Option<Template> {
+    let mut template = parse_template_bytes(&read_template_bytes(template_path).ok()?).ok()?;
+    if !template.entries.iter().any(|entry| matches!(entry, TemplateEntry::File(file) if file.blob.is_some())) {
+        return None;
+    }
+    if let Err(err) = load_blobs(&mut template, templates_dir) {
+        eprintln!("Cannot export '{}': {}.", template_path.display(), err);
+        std::process::exit(1);
+    }
+    for entry in &mut template.entries {
+        if let TemplateEntry::File(file) = entry {
+            file.blob = None;
+        }
+    }
+    // Blob contents are never compressed, so the inlined bodies aren't either.
+    template.header.retain(|(key, _)| key != "VOLUMES" && key != "COMPRESSION");
+    Some(template)
+}
+
+fn export_template(template_name: &str, output_dir: &Path) -> Option<PathBuf> {
+    check_template_name(template_name);
+    let templates_dir = get_templates_dir();
+    let template_path = template_file_path(&templates_dir, template_name);
+    if let Some(template) = template_path.exists().then(|| inline_blob_template(&templates_dir, &template_path)).flatten() {
+        fs::create_dir_all(output_dir).unwrap();
+        let gzip = is_gzip_path(&template_path);
+        let exported = output_dir.join(if gzip { gzip_file_name(template_name) } else { template_file_name(template_name) });
+        write_template_file(&exported, &template, gzip).unwrap();
+        println!("Template '{}' exported to '{:?}'", template_name, output_dir);
+        return Some(exported);
+    }
+    if template_path.exists() {
+        fs::create_dir_all(output_dir).unwrap();
+        let exported = output_dir.join(template_path.file_name().unwrap());
+        for file in template_files(&template_path) {
+            fs::copy(&file, output_dir.join(file.file_name().unwrap())).unwrap();
+        }
+        println!("Template '{}' exported to '{:?}'", template_name, output_dir);
+        Some(exported)
+    } else {
         println!("Template '{}' not found.", template_name);
+        None
+    }
+}
+
+fn export_templates_archive(template_names: &[String], archive_path: &Path) -> Option<PathBuf> {
+    let templates_dir = get_templates_dir();
+    if template_names.is_empty() {
+        println!("No templates to export.");
+        return None;
+    }
+    for template_name in template_names {
+        check_template_name(template_name);
+        if !template_file_path(&templates_dir, template_name).exists() {
+            println!("Template '{}' not found.", template_name);
+            return None;
+        }
+    }
+
+    if let Some(parent) = archive_path.parent() {
+        fs::create_dir_all(parent).unwrap();
+    }
+    let mut archive = zip::ZipWriter::new(File::create(archive_path).unwrap());
+    let options = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated);
+    for template_name in template_names {
+        let template_path = template_file_path(&templates_dir, template_name);
+        if let Some(template) = inline_blob_template(&templates_dir, &template_path) {
+            archive.start_file(template_file_name(template_name).as_str(), options).unwrap();
+            write_template(&mut archive, &template).unwrap();
+            println!("- {}", template_name);
+            continue;
+        }
+        if is_first_volume(&template_path) {
+            let Ok(content) = read_volumes(&template_path) else {
+                println!("Template '{}' is missing volumes.", template_name);
+                return None;
+            };
+            archive.start_file(template_file_name(template_name).as_str(), options).unwrap();
+            archive.write_all(&content).unwrap();
+            println!("- {}", template_name);
+            continue;
+        }
+        let file_name = template_path.file_name().unwrap().to_string_lossy().to_string();
+        archive.start_file(file_name.as_str(), options).unwrap();
+        let mut template_file = File::open(&template_path).unwrap();
+        std::io::copy(&mut template_file, &mut archive).unwrap();
+        println!("- {}", template_name);
+    }
+    archive.finish().unwrap();
+    println!("{} templates exported to '{:?}'", template_names.len(), archive_path);
+    Some(archive_path.to_path_buf())
+}
+
+fn import_templates_archive(archive_path: &Path) {
+    let mut archive = match zip::ZipArchive::new(File::open(archive_path).unwrap()) {
+        Ok(archive) => archive,
+        Err(err) => {
+            println!("Failed to read archive '{:?}': {}", archive_path, err);
+            return;
+        }
+    };
+
+    let templates_dir = get_templates_dir();
+    let mut imported = 0;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i).unwrap();
+        let Some((template_name, gzip)) = entry.enclosed_name()
+            .and_then(|path| path.file_name().and_then(|name| name.to_str()).map(String::from))
+            .and_then(|file_name| parse_template_file_name(&file_name).map(|(name, gzip)| (name.to_string(), gzip)))
+        else {
+            eprintln!("Skipping '{}': not a .{} template.", entry.name(), template_extension());
+            continue;
+        };
+        if let Err(reason) = validate_template_name(&template_name) {
+            eprintln!("Skipping '{}': invalid template name, {}.", entry.name(), reason);
+            continue;
+        }
+
+        let mut content = Vec::new();
+        let decoded = entry.read_to_end(&mut content)
+            .and_then(|_| if gzip { gunzip(&content) } else { Ok(content.clone()) });
+        let Ok(decoded) = decoded else {
+            eprintln!("Skipping '{}': not a valid template file.", entry.name());
+            continue;
+        };
+        if let Err(err) = parse_template_bytes(&decoded) {
+            eprintln!("Skipping '{}': template is malformed: {}.", entry.name(), err);
+            continue;
+        }
+
+        let file_name = if gzip { gzip_file_name(&template_name) } else { template_file_name(&template_name) };
+        fs::write(templates_dir.join(file_name), content).unwrap();
+        println!("- {}", template_name);
+        imported += 1;
+    }
+    println!("{} templates imported from '{:?}'", imported, archive_path);
+}
+
+fn import_template(input_file: &Path, template_name: Option<&str>, move_file: bool) {
+    if !verify_checksum_file(input_file) {
+        std::process::exit(1);
+    }
+    if input_file.extension().is_some_and(|ext| ext == "zip") {
+        if template_name.is_some() {
+            println!("A template name cannot be given when importing an archive.");
+            return;
+        }
+        if move_file {
+            eprintln!("Warning: --move is ignored for archives, '{:?}' is left in place.", input_file);
+        }
+        import_templates_archive(input_file);
+        return;
+    }
+    let file_name = input_file.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    let gzip = is_gzip_path(input_file);
+    let template_name = template_name
+        .or_else(|| parse_template_file_name(file_name).map(|(name, _)| name))
+        .unwrap_or_else(|| input_file.file_stem().unwrap().to_str().unwrap());
+    check_template_name(template_name);
+    let dest_path = get_templates_dir().join(if gzip { gzip_file_name(template_name) } else { template_file_name(template_name) });
+    if is_first_volume(input_file) {
+        import_volumes(input_file, template_name, &dest_path, move_file);
+        return;
+    }
+    let moved = move_file && (fs::rename(input_file, &dest_path).is_ok()
+        || fs::copy(input_file, &dest_path).and_then(|_| fs::remove_file(input_file)).is_ok());
+    if !moved {
+        fs::copy(input_file, &dest_path).unwrap();
+    }
+    println!("Template {} from '{:?}' as '{}'", if moved { "moved" } else { "copied" }, input_file, template_name);
+}
+
+fn import_volumes(first_volume: &Path, template_name: &str, dest_path: &Path, move_file: bool) {
+    let content = match read_volumes(first_volume) {
+        Ok(content) => content,
+        Err(err) => {
+            eprintln!("Cannot import '{:?}': {}.", first_volume, err);
+            std::process::exit(1);
+        }
+    };
+    if let Err(err) = parse_template_bytes(&content) {
+        eprintln!("Cannot import '{:?}': template is malformed: {}.", first_volume, err);
+        std::process::exit(1);
+    }
+    let volumes = volume_paths(first_volume);
+    fs::write(dest_path, content).unwrap();
+    if move_file {
+        for volume in &volumes {
+            let _ = fs::remove_file(volume);
+        }
+    }
+    println!("Template {} from {} volumes of '{:?}' as '{}'",
+        if move_file { "moved" } else { "joined" }, volumes.len(), first_volume, template_name);
+}
+
+#[cfg(not(target_os = "macos"))]
+fn run_quietly(program: &str, args: &[&str]) -> bool {
+    std::process::Command::new(program)
+        .args(args)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+#[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd"))]
+fn register_file_association() {
+    let Some(data_dir) = dirs::data_dir() else {
+        println!("Could not determine the user data directory.");
+        std::process::exit(1);
+    };
+    let exe = env::current_exe().unwrap();
+    let applications_dir = data_dir.join("applications");
+    let mime_dir = data_dir.join("mime");
+    fs::create_dir_all(&applications_dir).unwrap();
+    fs::create_dir_all(mime_dir.join("packages")).unwrap();
+
+    let desktop_entry = format!(
+        "[Desktop Entry]\nType=Application\nName=tdmcli\nComment=Import a tdmcli template\nExec=\"{}\" %f\nTerminal=true\nNoDisplay=true\nMimeType={};\n",
+        exe.display(), MIME_TYPE);
+    fs::write(applications_dir.join("tdmcli.desktop"), desktop_entry).unwrap();
+
+    let mime_package = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<mime-info xmlns=\"http://www.freedesktop.org/standards/shared-mime-info\">\n  <mime-type type=\"{}\">\n    <comment>tdmcli template</comment>\n    <glob pattern=\"*.{}\"/>\n  </mime-type>\n</mime-info>\n",
+        MIME_TYPE, template_extension());
+    fs::write(mime_dir.join("packages").join("tdmcli.xml"), mime_package).unwrap();
+
+    let mime_dir = mime_dir.to_string_lossy().to_string();
+    let applications_dir = applications_dir.to_string_lossy().to_string();
+    if !run_quietly("update-mime-database", &[&mime_dir]) {
+        println!("Warning: update-mime-database failed or is not installed, the .{} type may not be recognized until it runs.", template_extension());
+    }
+    run_quietly("update-desktop-database", &[&applications_dir]);
+    if !run_quietly("xdg-mime", &["default", "tdmcli.desktop", MIME_TYPE]) {
+        println!("Warning: xdg-mime failed or is not installed, tdmcli may not be the default application for .{} files.", template_extension());
+    }
+    println!(".{} files are now associated with {}", template_extension(), exe.display());
+}
+
+#[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd"))]
+fn unregister_file_association() {
+    let Some(data_dir) = dirs::data_dir() else {
+        println!("Could not determine the user data directory.");
+        std::process::exit(1);
+    };
+    let mime_dir = data_dir.join("mime");
+    let _ = fs::remove_file(data_dir.join("applications").join("tdmcli.desktop"));
+    let _ = fs::remove_file(mime_dir.join("packages").join("tdmcli.xml"));
+    run_quietly("update-mime-database", &[&mime_dir.to_string_lossy()]);
+    run_quietly("update-desktop-database", &[&data_dir.join("applications").to_string_lossy()]);
+    println!("The .{} file association has been removed.", template_extension());
+}
+
+#[cfg(target_os = "windows")]
+fn register_file_association() {
+    let exe = env::current_exe().unwrap();
+    let command = format!("\"{}\" \"%1\"", exe.display());
+    let extension_key = format!(r"HKCU\Software\Classes\.{}", template_extension());
+    let steps: [&[&str]; 3] = [
+        &["add", extension_key.as_str(), "/ve", "/d", "tdmcli.template", "/f"],
+        &["add", r"HKCU\Software\Classes\tdmcli.template", "/ve", "/d", "tdmcli template", "/f"],
+        &["add", r"HKCU\Software\Classes\tdmcli.template\shell\open\command", "/ve", "/d", command.as_str(), "/f"],
+    ];
+    for step in steps {
+        if !run_quietly("reg", step) {
+            println!("Failed to write the file association to the registry.");
+            std::process::exit(1);
+        }
+    }
+    println!(".{} files are now associated with {}", template_extension(), exe.display());
+}
+
+#[cfg(target_os = "windows")]
+fn unregister_file_association() {
+    run_quietly("reg", &["delete", &format!(r"HKCU\Software\Classes\.{}", template_extension()), "/f"]);
+    run_quietly("reg", &["delete", r"HKCU\Software\Classes\tdmcli.template", "/f"]);
+    println!("The .{} file association has been removed.", template_extension());
+}
+
+#[cfg(target_os = "macos")]
+fn register_file_association() {
+    println!("Registering a file association on macOS requires an application bundle, which tdmcli does not ship.");
+    println!("Use Finder's \"Get Info > Open with\" on a .{} file to pick tdmcli instead.", template_extension());
+}
+
+#[cfg(target_os = "macos")]
+fn unregister_file_association() {
+    println!("tdmcli does not register a file association on macOS, nothing to remove.");
+}
+
+fn update_url() -> String {
+    std::env::var("TDMCLI_UPDATE_URL").ok()
+        .filter(|url| !url.trim().is_empty())
+        .or_else(|| read_config().update_url)
+        .unwrap_or_else(|| UPDATE_URL.to_string())
+}
+
+fn get_latest_release_version() -> Result<String, reqwest::Error> {
+    let response = reqwest::blocking::get(update_url())?.error_for_status()?;
+    Ok(response.text()?.trim().to_string())
+}
+
+fn describe_update_error(err: &reqwest::Error) -> String {
+    if err.is_timeout() {
+        return "the request timed out".to_string();
+    }
+    if let Some(status) = err.status() {
+        return format!("the server responded with HTTP {}", status);
+    }
+    if err.is_connect() {
+        let mut source = std::error::Error::source(err);
+        while let Some(cause) = source {
+            if cause.to_string().contains("dns error") {
+                return "could not resolve the update server (DNS lookup failed)".to_string();
+            }
+            source = cause.source();
+        }
+        return "could not connect to the update server".to_string();
+    }
+    err.to_string()
+}
+
+fn check_for_updates() {
+    let spinner = ProgressBar::new_spinner();
+    spinner.set_style(ProgressStyle::default_spinner()
+        .template("{spinner:.green} {msg}")
+        .unwrap());
+    spinner.set_message("Checking for updates...");
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    let result = get_latest_release_version();
+    spinner.finish_and_clear();
+
+    match result {
+        Ok(latest_version) => {
+            println!("Update check completed.");
+            println!("Latest version available: {}", latest_version);
+            println!("Your current version: {}", VERSION);
+            if latest_version != VERSION {
+                println!("A new version is available! Download it from: {}", RELEASES_URL);
+            } else {
+                println!("You are using the latest version.");
+            }
+        }
+        Err(err) => {
+            println!("Failed to check for updates: {}.", describe_update_error(&err));
+        }
+    }
+}
+
+struct ServeError {
+    code: &'static str,
+    message: String,
+}
+
+fn serve_error(code: &'static str, message: impl Into<String>) -> ServeError {
+    ServeError { code, message: message.into() }
+}
+
+fn serve_string_param<'a>(request: &'a serde_json::Value, key: &str) -> Result<&'a str, ServeError> {
+    request[key].as_str().filter(|value| !value.is_empty())
+        .ok_or_else(|| serve_error("invalid_params", format!("'{}' must be a non-empty string", key)))
+}
+
+// Runs a command of this executable with `--json` in `dir` and returns its JSON summary line. Commands
+// run in a child process so that one that fails (and exits) doesn't end the server.
+fn serve_run(command: &str, request: &serde_json::Value, extra_args: &[&str]) -> Result<serde_json::Value, ServeError> {
+    let name = serve_string_param(request, "name")?;
+    let dir = match request["dir"].as_str() {
+        Some(dir) => normalize_path(Path::new(dir)),
+        None => env::current_dir().map_err(|err| serve_error("failed", err.to_string()))?,
+    };
+    if !dir.is_dir() {
+        return Err(serve_error("invalid_params", format!("'{}' is not a directory", dir.display())));
+    }
+    let mut args = vec![command.to_string(), name.to_string(), "--json".to_string()];
+    args.extend(extra_args.iter().map(|arg| arg.to_string()));
+    match &request["args"] {
+        serde_json::Value::Null => {}
+        serde_json::Value::Array(values) => {
+            for value in values {
+                let arg = value.as_str().ok_or_else(|| serve_error("invalid_params", "'args' must be an array of strings"))?;
+                args.push(arg.to_string());
+            }
+        }
+        _ => return Err(serve_error("invalid_params", "'args' must be an array of strings")),
+    }
+    let exe = env::current_exe().map_err(|err| serve_error("failed", err.to_string()))?;
+    let output = std::process::Command::new(exe)
+        .args(&args)
+        .current_dir(&dir)
+        .stdin(std::process::Stdio::null())
+        .output()
+        .map_err(|err| serve_error("failed", err.to_string()))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        let last_line = |output: &str| output.lines().rfind(|line| !line.trim().is_empty()).map(str::to_string);
+        let message = last_line(&stderr).or_else(|| last_line(&stdout))
+            .unwrap_or_else(|| "the command failed".to_string());
+        return Err(serve_error("failed", message));
+    }
+    let summary = stdout.lines().rev()
+        .find_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .unwrap_or(serde_json::Value::Null);
+    Ok(serde_json::json!({ "summary": summary, "warnings": stderr.lines().collect::<Vec<_>>() }))
+}
+
+// `get` overwrites existing files by default, so `apply` says which policy it wants either way.
+fn serve_conflict_args(request: &serde_json::Value) -> Result<&'static [&'static str], ServeError> {
+    let in_args = |flag: &str| request["args"].as_array().is_some_and(|args| args.iter().any(|arg| arg == flag));
+    match &request["overwrite"] {
+        serde_json::Value::Null if in_args("--overwrite") || in_args("--skip-existing") => Ok(&[]),
+        serde_json::Value::Bool(true) => Ok(&["--overwrite"]),
+        serde_json::Value::Bool(false) | serde_json::Value::Null => Ok(&["--skip-existing"]),
+        _ => Err(serve_error("invalid_params", "'overwrite' must be a boolean")),
+    }
+}
+
+fn serve_request(request: &serde_json::Value) -> Result<serde_json::Value, ServeError> {
+    let templates_dir = resolve_templates_dir();
+    let read_lock = || lock_store(&templates_dir, false).map_err(|err| serve_error("failed", err));
+    match request["cmd"].as_str() {
+        Some("version") => Ok(serde_json::json!({ "version": VERSION, "format_version": FORMAT_VERSION })),
+        Some("list") => {
+            if !templates_dir.is_dir() {
+                return Ok(serde_json::json!({ "templates": [] }));
+            }
+            let _lock = read_lock()?;
+            Ok(serde_json::json!({ "templates": template_names(&templates_dir) }))
+        }
+        Some("path") => {
+            let name = serve_string_param(request, "name")?;
+            let path = template_file_path(&templates_dir, name);
+            if !path.is_file() {
+                return Err(serve_error("not_found", format!("template '{}' not found", name)));
+            }
+            Ok(serde_json::json!({ "path": fs::canonicalize(&path).unwrap_or(path) }))
+        }
+        Some("show") => {
+            let name = serve_string_param(request, "name")?;
+            let _lock = read_lock()?;
+            let path = template_file_path(&templates_dir, name);
+            if !path.is_file() {
+                return Err(serve_error("not_found", format!("template '{}' not found", name)));
+            }
+            let template = read_template_bytes(&path)
+                .map_err(|err| err.to_string())
+                .and_then(|content| parse_template_bytes(&content).map_err(|err| err.to_string()))
+                .and_then(|mut template| load_blobs(&mut template, &templates_dir).map(|_| template).map_err(|err| err.to_string()))
+                .map_err(|err| serve_error("invalid_template", err))?;
+            let codec = template.codec();
+            let entries: Vec<serde_json::Value> = template.entries.iter()
+                .map(|entry| match entry {
+                    TemplateEntry::Dir(path) => serde_json::json!({ "type": "dir", "path": path }),
+                    TemplateEntry::File(file) => serde_json::json!({
+                        "type": "file",
+                        "path": file.path,
+                        "size": file.content_len(codec),
+                        "hash": file.hash,
+                    }),
+                    TemplateEntry::Delete(path) => serde_json::json!({ "type": "delete", "path": path }),
+                })
+                .collect();
+            Ok(serde_json::json!({ "name": name, "format_version": template.format_version, "entries": entries }))
+        }
+        Some("create") => serve_run("create", request, &[]),
+        Some("apply") => {
+            let name = serve_string_param(request, "name")?;
+            if !template_file_path(&templates_dir, name).is_file() {
+                return Err(serve_error("not_found", format!("template '{}' not found", name)));
+            }
+            serve_run("get", request, serve_conflict_args(request)?)
+        }
+        Some("delete") => {
+            let name = serve_string_param(request, "name")?;
+            validate_template_name(name).map_err(|reason| serve_error("invalid_params", format!("invalid template name: {}", reason)))?;
+            let _lock = lock_store(&templates_dir, true).map_err(|err| serve_error("failed", err))?;
+            if !remove_template(&templates_dir, name) {
+                return Err(serve_error("not_found", format!("template '{}' not found", name)));
+            }
+            Ok(serde_json::json!({ "deleted": name }))
+        }
+        Some(cmd) => Err(serve_error("unknown_command", format!("unknown command '{}'", cmd))),
+        None => Err(serve_error("invalid_request", "'cmd' must be a string")),
+    }
+}
+
+// Reads one JSON request per line on stdin and writes one JSON response per line on stdout,
+// until stdin is closed or a `shutdown` request arrives.
+fn serve() {
+    use std::io::BufRead;
+    let stdin = std::io::stdin();
+    let mut stdout = std::io::stdout();
+    for line in stdin.lock().lines() {
+        let Ok(line) = line else { break };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (id, result, shutdown) = match serde_json::from_str::<serde_json::Value>(&line) {
+            Ok(request) if request.is_object() => {
+                let shutdown = request["cmd"] == "shutdown";
+                let result = if shutdown { Ok(serde_json::json!({})) } else { serve_request(&request) };
+                (request["id"].clone(), result, shutdown)
+            }
+            Ok(_) => (serde_json::Value::Null, Err(serve_error("invalid_request", "a request must be a JSON object")), false),
+            Err(err) => (serde_json::Value::Null, Err(serve_error("parse_error", err.to_string())), false),
+        };
+        let response = match result {
+            Ok(result) => serde_json::json!({ "id": id, "ok": true, "result": result }),
+            Err(err) => serde_json::json!({ "id": id, "ok": false, "error": { "code": err.code, "message": err.message } }),
+        };
+        if writeln!(stdout, "{}", response).and_then(|_| stdout.flush()).is_err() || shutdown {
+            break;
+        }
+    }
+}
+
+fn check_for_update_normalize() {
+    if let Ok(latest_version) = get_latest_release_version() {
+        if latest_version != VERSION {
+            println!("A new version is available! Download it from GitHub.");
+        }
     }
 }
 
-fn show_template_directory() {
-    let dir = get_templates_dir();
-    println!("Templates directory: {}", dir.display());
+fn print_usage() {
+    println!(r#"Usage: tdmcli <command> [arguments]
+
+Examples:
+  tdmcli create [template_name] [--hiddenfolder] [--excludeignore] [--ext <ext>]... [--exclude-ext <ext>]...
+                [--root-marker <marker>]... [--exclude-vcs | --include-vcs] [--preserve-owner] [--preserve-xattrs] [--preserve-root-name] [--verbose | --explain-ignores] [--format-version <n>] [--encoding base64|base64url|hex] [--ignore-case | --no-ignore-case] [-o <file>] [--since <git_ref>] [--dry-run] [--emit-summary] [--skeleton] [--text-only] [--skip-lfs] [--no-source] [--wrap <cols>] [--cache] [--gzip] [--split <size>] [--byte-paths] [--hash-names] [--encrypt [--password <passphrase>] [--store-password]] [--no-compress] [--env-tag <env>=<glob>]... [--stdin-content --as <path>] [--exclude-symlinks] [--empty-dir-marker <file>] [--description <text> | --from-readme] [--keep-going] [--watch] [--quiet] [--json]
+                                   Create a template (include hidden folders if flag provided, exclude .tdmignore if flag provided,
+                                   keep only files with the given extensions / drop files with the given extensions,
+                                   read .tdmignore from the nearest parent directory containing one of the markers,
+                                   .git/.svn/.hg/.bzr/CVS directories are left out unless --include-vcs is given,
+                                   --preserve-owner records file uid/gid to restore them on get, Unix only,
+                                   --preserve-xattrs records extended attributes to restore them on get, Unix only,
+                                   --preserve-root-name stores paths under the current folder's name so get recreates it,
+                                   --verbose/--explain-ignores warns about .tdmignore patterns that matched nothing,
+                                   --format-version 1 writes the original format readable by older tdmcli versions,
+                                   --encoding chooses how file contents are stored as text (base64 by default),
+                                   -o/--output writes the template to <file> instead of the templates directory,
+                                   --since keeps only the files changed since <git_ref> (including untracked files),
+                                   --dry-run lists the files and empty directories that would be captured, without writing,
+                                   --emit-summary also writes <name>.json describing the files of the template,
+                                   --skeleton records every directory and every file path but no file contents,
+                                   --text-only (or --exclude-binary) leaves binary files out,
+                                   --wrap splits the encoded content of each file into lines of at most <cols> characters,
+                                   --cache reuses the encoded content of files unchanged since the previous --cache run,
+                                   --gzip stores the template gzip-compressed as <name>.tdmcli.gz,
+                                   --split <size> (e.g. 100M) writes volumes <name>.tdmcli.001, .002, ... of at most <size>,
+                                   --stdin-content --as <path> makes a template of a single file <path> read from stdin,
+                                   symlinks are skipped with a warning listing them, or silently with --exclude-symlinks,
+                                   --byte-paths stores paths percent-encoded so non-UTF-8 file names are kept exactly,
+                                   --no-source doesn't record the source directory (used by list --stale),
+                                   --skip-lfs leaves out the files tracked by git-lfs (filter=lfs in .gitattributes) and LFS pointers,
+                                   --hash-names stores each file content once in a blob directory shared by all templates,
+                                   --encrypt encrypts file contents with AES-256-GCM and a key derived from a passphrase,
+                                   --store-password also keeps that passphrase in the system keyring for get and verify,
+                                   file contents are gzip-compressed inside the template, unless --no-compress is given,
+                                   --env-tag prod=config/prod.toml tags matching files so get applies them only with --env prod,
+                                   --empty-dir-marker .gitkeep stores an empty <file> in each empty directory instead of a DIR entry,
+                                   --description (or --from-readme: the first paragraph of README.md) describes the template,
+                                   --watch recreates the template whenever a file that is not ignored changes, until Ctrl-C;
+                                   defaults for the name and all these options are read from .tdmcli.toml if present).
+  tdmcli get [template_name] [--overwrite | --skip-existing] [--skip-unchanged] [--merge-json] [--merge-toml]
+             [--merge-prefer existing|template] [--chmod <mode>] [--umask <mask>] [--owner-map [<uid>:<gid>=<uid>:<gid>]]... [--env <env>] [--git-add] [--dirs-only] [--check] [--keep-going] [--conflict-report <file>] [--backup | --backup-dir <dir>] [--only <glob>]... [--except <glob>]... [--interactive] [--strict] [--empty-dir-marker <file>] [--print-tree-after] [--protect <glob>]... [--dir <templates_dir>] [--output-dir <dir>] [--prefix <subdir>] [--password <passphrase>] [--no-keyring]
+             [--strip-components <n>] [--expand-env [--undefined-env error|keep] [--allow-absolute-env]] [--no-lock] [--list-only] [--quiet] [--json]
+                                   Apply the template (overwrite existing files, the default, or keep them;
+                                   with --skip-unchanged, existing files that already have the template's content are not rewritten,
+                                   with --merge-json/--merge-toml, existing .json/.toml files are deep-merged with the template's,
+                                   --chmod sets a fixed (octal) mode on every written file, --umask clears bits from it (Unix only),
+                                   --owner-map restores recorded owners as the current user, or as the mapped uid:gid (Unix only),
+                                   --env applies the files tagged with <env> too (untagged files are always applied, tagged ones only then),
+                                   --git-add stages the files written or deleted by the template with git add,
+                                   --dirs-only only creates the directories of the template, without writing any file,
+                                   --check runs every validation of the apply (decoding, hashes, conflicts, free space)
+                                   without writing anything, and exits with 1 if the apply would fail,
+                                   --conflict-report lists the existing files that would be overwritten in <file> and
+                                   aborts if there are any, unless --overwrite is given,
+                                   with --backup, overwritten files are first copied to <file>.tdmcli.bak,
+                                   with --backup-dir, they are copied into a new <unix time>-<name> directory under <dir>,
+                                   --only/--except apply only the entries whose path matches / doesn't match a glob,
+                                   --interactive lets you uncheck entries in a terminal selector before applying,
+                                   --strict refuses a template with two entries for the same file (the last one wins otherwise),
+                                   --empty-dir-marker creates the directory of each empty <file> marker instead of the marker,
+                                   --print-tree-after shows the files and directories written as a tree at the end,
+                                   --protect never overwrites or deletes existing files matching <glob>, even with --overwrite,
+                                   --output-dir applies the template into <dir> (created if missing) instead of the current directory,
+                                   with --prefix, every file and directory is created inside <subdir>,
+                                   with --strip-components, the first <n> path components of every entry are dropped,
+                                   with --expand-env, $VAR and ${{VAR}} in paths are replaced by environment variables
+                                   (paths that become absolute need --allow-absolute-env),
+                                   with --list-only, nothing is written and the template's content is shown as a tree;
+                                   an encrypted template uses --password, else the passphrase stored in the system keyring
+                                   (unless --no-keyring is given), else asks for it).
+                                   Applied templates are recorded in tdmcli.lock (unless --no-lock is given); without
+                                   a template name, every template recorded in tdmcli.lock is applied again.
+                                   create and get accept --jobs <n> to limit the number of worker threads,
+                                   --no-progress to hide the progress bar, --timings to print phase durations to stderr
+                                   and --profile-json <file> to write timings, threads, throughput and file sizes as JSON.
+                                   create and get end with a summary line, printed as JSON with --json and
+                                   left out (together with all other progress output) with --quiet.
+  tdmcli tree <template_name> [--dir <templates_dir>] [--output-format tree|flat|paths]
+                                   Show the files and directories of a template as a tree, with file sizes
+                                   (flat: a sorted bullet list, paths: one path per line).
+  tdmcli verify <template_name> [--deep] [--password <passphrase>] [--no-keyring] [--dir <templates_dir>]
+                                   Check that a template is well-formed; with --deep, also decode every file and check its HASH.
+  tdmcli delete <template_name>    Delete a template.
+  tdmcli repair <template_name>    Recompute the SIZE/HASH metadata of a template.
+  tdmcli migrate <template_name>... | --all [--backup]
+                                   Rewrite templates made in an older format version in the current one (with --backup,
+                                   the original files are kept as <file>.v<version>.bak); current templates are skipped.
+  tdmcli touch <template_name>     Set the modification time of a template to now, without changing it.
+  tdmcli mv-in <template_name> <old_path> <new_path>
+                                   Rename a file or directory inside a template, without changing contents.
+  tdmcli list [--dir <templates_dir>] [--output-format flat|paths|tree] [--verbose] [--stale]
+                                   Show all templates (of the given directory instead of the templates directory),
+                                   with their description with --verbose, and whether they still match the directory
+                                   they were made from with --stale;
+                                   paths prints the absolute path of each template file, tree the content of each one.
+  tdmcli import <input_file> [template_name] [--move]
+                                   Import an external template (or every template in a .zip archive);
+                                   --move moves the file into the templates directory instead of copying it.
+  tdmcli export <template_name> <output_dir>        Export template (with --with-checksum, also write <file>.sha256
+                                                    for every export form; import verifies a .sha256 next to the file).
+                                                    <template_name> may be a glob (e.g. "rust-*") to export every match.
+  tdmcli export <template_name>... <output.zip>     Export several templates into a zip archive.
+  tdmcli export --all <output_dir>                  Export all templates into <output_dir>/tdmcli-templates.zip.
+  tdmcli validate-ignore [--ignore-file <path>] [--hiddenfolder] [--include-vcs] [--ignore-case | --no-ignore-case]
+                                   Show which files of the current directory .tdmignore (or the given file) includes or ignores.
+  tdmcli register                  Associate .tdmcli files with tdmcli so opening one imports it.
+  tdmcli unregister                Remove the .tdmcli file association.
+  tdmcli show-dir                  Show the directory where templates are stored.
+  tdmcli serve                     Read JSON requests from stdin, one per line, and write one JSON response per line
+                                   to stdout, for editor integrations (see the README for the protocol).
+  tdmcli stats [--count-files] [--json]
+                                   Show the number and total size of the templates, the largest and the oldest/newest ones.
+  tdmcli path <template_name> [--dir <templates_dir>]
+                                   Print the absolute path of a template's file.
+  tdmcli change-dir <new_directory>   Change template directory.
+  tdmcli change-dir - (or --reset) Go back to the default templates directory.
+  tdmcli -v                        Show the current version.
+  tdmcli -u                        Check for updates.
+  tdmcli help                      Show this help.
+
+Every command also accepts --log-level <off|error|warn|info|debug|trace> (default warn, or RUST_LOG)
+to print diagnostic logs about the walk, ignore matching and apply phases to stderr.
+Commands that ask for confirmation (delete) accept -y/--assume-yes to answer yes without asking;
+when stdin is not a terminal they answer yes too, unless --no-assume-yes is given.
+create and get stop at the first file they cannot read or write; with --keep-going they process
+everything else, report all errors at the end and exit with code 1."#);
 }
 
-fn list_templates() {
-    let templates_dir = get_templates_dir();
-    let templates: Vec<_> = fs::read_dir(&templates_dir)
-        .unwrap()
-        .filter_map(|entry| entry.ok())
-        .filter(|entry| entry.path().extension()
-            .map(|ext| ext == "tdmcli")
-            .unwrap_or(false))
-        .map(|entry| entry.file_name().into_string().unwrap().replace(".tdmcli", ""))
+fn flag_values(args: &[String], flag: &str) -> Vec<String> {
+    args.windows(2)
+        .filter(|pair| pair[0] == flag)
+        .map(|pair| pair[1].clone())
+        .collect()
+}
+
+fn parse_format_version(args: &[String], default: Option<u32>) -> u32 {
+    let Some(value) = flag_values(args, "--format-version").pop().or(default.map(|version| version.to_string())) else {
+        return FORMAT_VERSION;
+    };
+    match value.parse::<u32>() {
+        Ok(version) if (1..=FORMAT_VERSION).contains(&version) => version,
+        _ => {
+            println!("Unsupported --format-version '{}' (supported: 1 to {}).", value, FORMAT_VERSION);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_encoding(args: &[String]) -> Encoding {
+    let Some(value) = flag_values(args, "--encoding").pop() else {
+        return Encoding::Base64;
+    };
+    Encoding::parse(&value).unwrap_or_else(|| {
+        println!("Unsupported --encoding '{}' (supported: base64, base64url, hex).", value);
+        std::process::exit(1);
+    })
+}
+
+fn parse_prefix(args: &[String]) -> Option<PathBuf> {
+    let value = flag_values(args, "--prefix").pop()?;
+    let prefix = PathBuf::from(&value);
+    if !is_safe_relative_path(&prefix) {
+        println!("Invalid --prefix '{}': it must be a relative path without '..'.", value);
+        std::process::exit(1);
+    }
+    Some(prefix)
+}
+
+fn resolve_ignore_case(args: &[String], project_default: Option<bool>) -> bool {
+    if args.iter().any(|arg| arg == "--ignore-case") {
+        return true;
+    }
+    if args.iter().any(|arg| arg == "--no-ignore-case") {
+        return false;
+    }
+    project_default.or_else(|| read_config().ignore_case).unwrap_or(cfg!(any(target_os = "windows", target_os = "macos")))
+}
+
+fn parse_merge_prefer(args: &[String]) -> MergePreference {
+    match flag_values(args, "--merge-prefer").pop().as_deref() {
+        None | Some("existing") => MergePreference::Existing,
+        Some("template") => MergePreference::Template,
+        Some(value) => {
+            println!("Invalid value for --merge-prefer: '{}' (expected existing or template).", value);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_expand_env(args: &[String]) -> Option<UndefinedEnv> {
+    if !args.iter().any(|arg| arg == "--expand-env") {
+        return None;
+    }
+    match flag_values(args, "--undefined-env").pop().as_deref() {
+        None | Some("error") => Some(UndefinedEnv::Error),
+        Some("keep") => Some(UndefinedEnv::Keep),
+        Some(value) => {
+            println!("Invalid value for --undefined-env: '{}' (expected error or keep).", value);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_env_tags(args: &[String]) -> Vec<(String, globset::GlobMatcher)> {
+    flag_values(args, "--env-tag").into_iter()
+        .map(|value| {
+            let tag = value.split_once('=')
+                .filter(|(env, _)| !env.is_empty() && !env.contains(char::is_whitespace))
+                .and_then(|(env, pattern)| Some((env.to_string(), Glob::new(pattern).ok()?.compile_matcher())));
+            tag.unwrap_or_else(|| {
+                println!("Invalid value for --env-tag: '{}' (expected <env>=<glob>, e.g. prod=config/prod.toml).", value);
+                std::process::exit(1);
+            })
+        })
+        .collect()
+}
+
+fn parse_owner_map(args: &[String]) -> Option<Vec<OwnerMapping>> {
+    if !args.iter().any(|arg| arg == "--owner-map") {
+        return None;
+    }
+    if cfg!(not(unix)) {
+        eprintln!("Warning: --owner-map is only supported on Unix and is ignored.");
+    }
+    let mappings = flag_values(args, "--owner-map").into_iter()
+        .filter(|value| !value.starts_with('-') && value.contains('='))
+        .map(|value| {
+            let mapping = value.split_once('=').and_then(|(from, to)| Some((parse_owner(from)?, parse_owner(to)?)));
+            mapping.unwrap_or_else(|| {
+                println!("Invalid value for --owner-map: '{}' (expected <uid>:<gid>=<uid>:<gid>).", value);
+                std::process::exit(1);
+            })
+        })
         .collect();
+    Some(mappings)
+}
 
-    if templates.is_empty() {
-        println!("No templates found.");
-    } else {
-        println!("Available templates:");
-        for template in templates {
-            println!("- {}", template);
+fn parse_mode(args: &[String], flag: &str) -> Option<u32> {
+    let value = flag_values(args, flag).pop()?;
+    match u32::from_str_radix(&value, 8) {
+        Ok(mode) if mode <= 0o7777 => {
+            if cfg!(not(unix)) {
+                eprintln!("Warning: {} is only supported on Unix and is ignored.", flag);
+            }
+            Some(mode)
+        }
+        _ => {
+            println!("Invalid value for {}: '{}' (expected an octal mode such as 0644).", flag, value);
+            std::process::exit(1);
         }
     }
 }
 
-fn show_version() {
-    println!("tdmcli version: {}", VERSION);
+fn parse_strip_components(args: &[String]) -> usize {
+    let Some(value) = flag_values(args, "--strip-components").pop() else {
+        return 0;
+    };
+    value.parse::<usize>().unwrap_or_else(|_| {
+        println!("Invalid value for --strip-components: '{}' (expected a number).", value);
+        std::process::exit(1);
+    })
 }
 
-fn export_template(template_name: &str, output_dir: &Path) {
-    let template_path = get_templates_dir().join(format!("{}.tdmcli", template_name));
-    if template_path.exists() {
-        fs::create_dir_all(output_dir).unwrap();
-        fs::copy(&template_path, output_dir.join(format!("{}.tdmcli", template_name))).unwrap();
-        println!("Template '{}' exported to '{:?}'", template_name, output_dir);
-    } else {
-        println!("Template '{}' not found.", template_name);
+fn parse_empty_dir_marker(args: &[String]) -> Option<String> {
+    let marker = flag_values(args, "--empty-dir-marker").pop()?;
+    if marker.is_empty() || marker == "." || marker == ".." || marker.contains(['/', '\\']) {
+        println!("Invalid value for --empty-dir-marker: '{}' (expected a file name such as .gitkeep).", marker);
+        std::process::exit(1);
     }
+    Some(marker)
 }
 
-fn import_template(input_file: &Path, template_name: Option<&str>) {
-    let template_name = template_name.unwrap_or_else(|| {
-        input_file.file_stem().unwrap().to_str().unwrap()
-    });
-    let dest_path = get_templates_dir().join(format!("{}.tdmcli", template_name));
-    fs::copy(input_file, &dest_path).unwrap();
-    println!("Template imported from '{:?}' as '{}'", input_file, template_name);
+fn parse_glob_set(args: &[String], flag: &str) -> Option<GlobSet> {
+    let patterns = flag_values(args, flag);
+    if patterns.is_empty() {
+        return None;
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in &patterns {
+        match Glob::new(pattern.trim_end_matches('/')) {
+            Ok(glob) => {
+                builder.add(glob);
+            }
+            Err(err) => {
+                println!("Invalid pattern for {}: '{}' ({}).", flag, pattern, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    Some(builder.build().unwrap())
 }
 
-fn get_latest_release_version() -> Option<String> {
-    let url = "https://raw.githubusercontent.com/MrTigerST/tdmcli/main/version";
-    match reqwest::blocking::get(url) {
-        Ok(response) => match response.text() {
-            Ok(text) => Some(text.trim().to_string()),
-            Err(_) => None,
-        },
-        Err(_) => None,
+fn parse_wrap(args: &[String]) -> Option<usize> {
+    let value = flag_values(args, "--wrap").pop()?;
+    match value.parse::<usize>() {
+        Ok(columns) if columns > 0 => Some(columns),
+        _ => {
+            println!("Invalid value for --wrap: '{}' (expected a positive number of columns).", value);
+            std::process::exit(1);
+        }
     }
 }
 
-fn check_for_updates() {
-    if let Some(latest_version) = get_latest_release_version() {
-        println!("Latest version available: {}", latest_version);
-        println!("Your current version: {}", VERSION);
-        if latest_version != VERSION {
-            println!("A new version is available! Download it from GitHub.");
-        } else {
-            println!("You are using the latest version.");
+// The template path given with `--as` when the content of a single file is read from stdin.
+fn parse_stdin_path(args: &[String]) -> Option<String> {
+    let stdin_content = args.iter().any(|arg| arg == "--stdin-content");
+    let path = flag_values(args, "--as").pop();
+    if !stdin_content {
+        if path.is_some() {
+            println!("--as can only be used together with --stdin-content.");
+            std::process::exit(1);
         }
-    } else {
-        println!("Failed to check for updates.");
+        return None;
+    }
+    let Some(path) = path else {
+        println!("--stdin-content needs --as <path> for the path of the file in the template.");
+        std::process::exit(1);
+    };
+    let path = path.replace('\\', "/");
+    let trimmed = path.trim_start_matches("./");
+    if trimmed.is_empty() || trimmed.ends_with('/') || !is_safe_relative_path(Path::new(trimmed)) {
+        println!("Invalid value for --as: '{}' (expected a relative file path without '..').", path);
+        std::process::exit(1);
+    }
+    if ["--watch", "--since", "--dry-run"].iter().any(|flag| args.iter().any(|arg| arg == flag)) {
+        println!("--stdin-content cannot be combined with --watch, --since or --dry-run.");
+        std::process::exit(1);
     }
+    Some(trimmed.to_string())
 }
 
-fn check_for_update_normalize() {
-    if let Some(latest_version) = get_latest_release_version() {
-        if latest_version != VERSION {
-            println!("A new version is available! Download it from GitHub.");
+// Sizes such as `4096`, `512K`, `100M` or `2GB`, with binary multiples.
+fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let value = value.strip_suffix(['B', 'b']).unwrap_or(value);
+    let (digits, multiplier) = match value.char_indices().last()? {
+        (index, 'K' | 'k') => (&value[..index], 1 << 10),
+        (index, 'M' | 'm') => (&value[..index], 1 << 20),
+        (index, 'G' | 'g') => (&value[..index], 1 << 30),
+        _ => (value, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+// Returns the cipher of `create --encrypt` and its passphrase.
+fn parse_encryption(args: &[String]) -> Option<(TemplateCipher, String)> {
+    if !args.iter().any(|arg| arg == "--encrypt") {
+        if args.iter().any(|arg| arg == "--store-password") {
+            println!("--store-password needs --encrypt.");
+            std::process::exit(1);
+        }
+        return None;
+    }
+    if args.iter().any(|arg| arg == "--hash-names" || arg == "--cache") {
+        println!("--encrypt cannot be combined with --hash-names or --cache.");
+        std::process::exit(1);
+    }
+    let passphrase = match flag_values(args, "--password").pop() {
+        Some(password) => Ok(password),
+        None => read_passphrase("Passphrase for the template", true),
+    };
+    match passphrase.and_then(|passphrase| match passphrase.is_empty() {
+        true => Err("the passphrase cannot be empty".to_string()),
+        false => TemplateCipher::new(&passphrase).map(|cipher| (cipher, passphrase)),
+    }) {
+        Ok(encryption) => Some(encryption),
+        Err(err) => {
+            println!("Cannot encrypt the template: {}.", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_split(args: &[String]) -> Option<u64> {
+    let value = flag_values(args, "--split").pop()?;
+    match parse_size(&value) {
+        Some(limit) if limit > 0 => Some(limit),
+        _ => {
+            println!("Invalid value for --split: '{}' (expected a size like 100M, 512K or 4096).", value);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_jobs(args: &[String]) -> Option<usize> {
+    let value = flag_values(args, "--jobs").pop()?;
+    match value.parse::<usize>() {
+        Ok(jobs) if jobs > 0 => Some(jobs),
+        _ => {
+            println!("Invalid value for --jobs: '{}' (expected a positive number).", value);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_with_jobs<R: Send>(jobs: Option<usize>, operation: impl FnOnce() -> R + Send) -> R {
+    match jobs {
+        Some(jobs) => rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build()
+            .unwrap()
+            .install(operation),
+        None => operation(),
+    }
+}
+
+fn init_logging(args: &mut Vec<String>) {
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"));
+    if let Some(index) = args.iter().position(|arg| arg == "--log-level") {
+        let value = args.get(index + 1).cloned().unwrap_or_default();
+        let Ok(level) = value.parse::<log::LevelFilter>() else {
+            println!("Invalid value for --log-level: '{}' (expected off, error, warn, info, debug or trace).", value);
+            std::process::exit(1);
+        };
+        args.drain(index..(index + 2).min(args.len()));
+        builder.filter_level(level.min(log::LevelFilter::Warn)).filter_module("tdmcli", level);
+    }
+    builder.init();
+}
+
+fn init_assume_yes(args: &mut Vec<String>) {
+    let mut assume_yes = None;
+    args.retain(|arg| match arg.as_str() {
+        "-y" | "--assume-yes" => {
+            assume_yes = Some(true);
+            false
         }
+        "--no-assume-yes" => {
+            assume_yes = Some(false);
+            false
+        }
+        _ => true,
+    });
+    let _ = ASSUME_YES.set(assume_yes);
+}
+
+// Writing to a closed pipe (e.g. `tdmcli tree <name> | head`) should end tdmcli quietly, like other Unix tools,
+// instead of panicking in println!.
+#[cfg(unix)]
+fn reset_sigpipe() {
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_DFL);
     }
 }
 
+#[cfg(not(unix))]
+fn reset_sigpipe() {}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    reset_sigpipe();
+    let mut args: Vec<String> = env::args().collect();
+    init_logging(&mut args);
+    init_assume_yes(&mut args);
     if args.len() < 2 {
-        println!(r#"Usage: tdmcli <command> [arguments]
-
-Examples:
-  tdmcli create <template_name> [--hiddenfolder] [--excludeignore]   Create a template (include hidden folders if flag provided, exclude .tdmignore if flag provided).
-  tdmcli get <template_name>       Apply the template.
-  tdmcli delete <template_name>    Delete a template.
-  tdmcli list                      Show all templates.
-  tdmcli import <input_file> [template_name]      Import an external template.
-  tdmcli export <template_name> <output_dir>        Export template.
-  tdmcli show-dir                  Show the directory where templates are stored.
-  tdmcli change-dir <new_directory>   Change template directory.
-  tdmcli -v                        Show the current version.
-  tdmcli -u                        Check for updates.
-  tdmcli help                      Show this help."#);
+        print_usage();
         return;
     }
 
-    if args[1].ends_with(".tdmcli") {
-        println!("Detected .tdmcli file, importing...");
-        import_template(Path::new(&args[1]), None);
+    if parse_template_file_name(&args[1]).is_some() {
+        println!("Detected .{} file, importing...", template_extension());
+        let _lock = lock_store_or_exit(&get_templates_dir(), true);
+        import_template(&normalize_path(Path::new(&args[1])), None, false);
         return;
     }
 
     match args[1].as_str() {
-        "create" if args.len() >= 3 => {
-            let include_hidden = args.iter().any(|arg| arg == "--hiddenfolder");
-            let exclude_ignore = args.iter().any(|arg| arg == "--excludeignore");
-            check_for_update_normalize();
-            create_template(&args[2], &env::current_dir().unwrap(), include_hidden, exclude_ignore)
+        "create" => {
+            let project: ProjectConfig = read_toml_file(Path::new(PROJECT_FILE_NAME)).unwrap_or_default();
+            let Some(template_name) = args.get(2).filter(|arg| !arg.starts_with('-')).cloned().or(project.name) else {
+                println!("No template name given and no name set in {}.", PROJECT_FILE_NAME);
+                print_usage();
+                std::process::exit(1);
+            };
+            check_template_name(&template_name);
+            let flag_or = |flag: &str, default: Vec<String>| {
+                let values = flag_values(&args, flag);
+                if values.is_empty() { default } else { values }
+            };
+            let (encryption, passphrase) = parse_encryption(&args).unzip();
+            let options = CreateOptions {
+                include_hidden: project.hidden_folders || args.iter().any(|arg| arg == "--hiddenfolder"),
+                exclude_ignore: project.exclude_ignore || args.iter().any(|arg| arg == "--excludeignore"),
+                extensions: flag_or("--ext", project.ext).iter().map(|ext| normalize_extension(ext)).collect(),
+                excluded_extensions: flag_or("--exclude-ext", project.exclude_ext).iter().map(|ext| normalize_extension(ext)).collect(),
+                root_markers: flag_or("--root-marker", project.root_markers),
+                exclude_vcs: args.iter().any(|arg| arg == "--exclude-vcs")
+                    || !(project.include_vcs || args.iter().any(|arg| arg == "--include-vcs")),
+                extra_ignores: project.ignore,
+                ignore_case: resolve_ignore_case(&args, project.ignore_case),
+                preserve_owner: project.preserve_owner || args.iter().any(|arg| arg == "--preserve-owner"),
+                preserve_xattrs: project.preserve_xattrs || args.iter().any(|arg| arg == "--preserve-xattrs"),
+                preserve_root_name: project.preserve_root_name || args.iter().any(|arg| arg == "--preserve-root-name"),
+                explain_ignores: args.iter().any(|arg| arg == "--verbose" || arg == "--explain-ignores"),
+                format_version: parse_format_version(&args, project.format_version),
+                encoding: parse_encoding(&args),
+                output: flag_values(&args, "-o").into_iter().chain(flag_values(&args, "--output")).last()
+                    .map(|output| normalize_path(Path::new(&output))),
+                since: flag_values(&args, "--since").pop(),
+                dry_run: args.iter().any(|arg| arg == "--dry-run"),
+                emit_summary: args.iter().any(|arg| arg == "--emit-summary"),
+                skeleton: args.iter().any(|arg| arg == "--skeleton"),
+                text_only: args.iter().any(|arg| arg == "--text-only" || arg == "--exclude-binary"),
+                wrap: parse_wrap(&args),
+                cache: args.iter().any(|arg| arg == "--cache"),
+                gzip: args.iter().any(|arg| arg == "--gzip"),
+                split: parse_split(&args),
+                byte_paths: args.iter().any(|arg| arg == "--byte-paths"),
+                hash_names: args.iter().any(|arg| arg == "--hash-names"),
+                encryption,
+                compress: !args.iter().any(|arg| arg == "--no-compress"),
+                skip_lfs: args.iter().any(|arg| arg == "--skip-lfs"),
+                record_source: !args.iter().any(|arg| arg == "--no-source"),
+                env_tags: parse_env_tags(&args),
+                stdin_path: parse_stdin_path(&args),
+                exclude_symlinks: args.iter().any(|arg| arg == "--exclude-symlinks"),
+                empty_dir_marker: parse_empty_dir_marker(&args),
+                description: flag_values(&args, "--description").pop().map(|description| one_line(&description))
+                    .filter(|description| !description.is_empty()),
+                from_readme: args.iter().any(|arg| arg == "--from-readme"),
+                profile_json: flag_values(&args, "--profile-json").pop().map(|path| normalize_path(Path::new(&path))),
+                keep_going: args.iter().any(|arg| arg == "--keep-going"),
+                watch: args.iter().any(|arg| arg == "--watch"),
+                no_progress: args.iter().any(|arg| arg == "--no-progress"),
+                timings: args.iter().any(|arg| arg == "--timings"),
+                quiet: args.iter().any(|arg| arg == "--quiet"),
+                json: args.iter().any(|arg| arg == "--json"),
+            };
+            // Blobs are stored uncompressed, so that identical files share one whatever the template.
+            let options = CreateOptions { compress: options.compress && options.format_version > 1 && !options.hash_names, ..options };
+            if !options.quiet && !options.json {
+                check_for_update_normalize();
+            }
+            let root_dir = env::current_dir().unwrap();
+            install_interrupt_handler();
+            run_with_jobs(parse_jobs(&args), || {
+                let created = create_template(&template_name, &root_dir, &options);
+                if let Some(passphrase) = passphrase.as_deref().filter(|_| created && args.iter().any(|arg| arg == "--store-password")) {
+                    match store_keyring_passphrase(&template_name, passphrase) {
+                        Ok(()) if options.quiet || options.json => {}
+                        Ok(()) => println!("Passphrase of '{}' stored in the system keyring.", template_name),
+                        Err(err) => eprintln!("Warning: could not store the passphrase in the system keyring ({}); give it to get with --password.", err),
+                    }
+                }
+                if options.watch {
+                    watch_and_recreate(&template_name, &root_dir, &options);
+                } else if !created {
+                    std::process::exit(1);
+                }
+            })
         }
-        "get" if args.len() == 3 => {
-            check_for_update_normalize();
-            apply_template(&args[2])
+        "get" => {
+            let overwrite = args.iter().any(|arg| arg == "--overwrite");
+            let skip_existing = args.iter().any(|arg| arg == "--skip-existing");
+            if overwrite && skip_existing {
+                println!("--overwrite and --skip-existing cannot be used together.");
+                std::process::exit(1);
+            }
+            if args.iter().any(|arg| arg == "--backup") && args.iter().any(|arg| arg == "--backup-dir") {
+                println!("--backup and --backup-dir cannot be used together.");
+                std::process::exit(1);
+            }
+            let options = ApplyOptions {
+                conflict_policy: if skip_existing { ConflictPolicy::SkipExisting } else { ConflictPolicy::Overwrite },
+                backup: args.iter().any(|arg| arg == "--backup"),
+                backup_dir: flag_values(&args, "--backup-dir").pop().map(|dir| normalize_path(Path::new(&dir))),
+                only: parse_glob_set(&args, "--only"),
+                except: parse_glob_set(&args, "--except"),
+                interactive: args.iter().any(|arg| arg == "--interactive"),
+                strict: args.iter().any(|arg| arg == "--strict"),
+                empty_dir_marker: parse_empty_dir_marker(&args),
+                print_tree_after: args.iter().any(|arg| arg == "--print-tree-after"),
+                protect: parse_glob_set(&args, "--protect"),
+                profile_json: flag_values(&args, "--profile-json").pop().map(|path| normalize_path(Path::new(&path))),
+                templates_dir: flag_values(&args, "--dir").pop().map(|dir| normalize_path(Path::new(&dir))),
+                password: flag_values(&args, "--password").pop(),
+                keyring: !args.iter().any(|arg| arg == "--no-keyring"),
+                output_dir: flag_values(&args, "--output-dir").pop().map(|dir| normalize_path(Path::new(&dir))),
+                prefix: parse_prefix(&args),
+                strip_components: parse_strip_components(&args),
+                expand_env: parse_expand_env(&args),
+                allow_absolute_env: args.iter().any(|arg| arg == "--allow-absolute-env"),
+                skip_unchanged: args.iter().any(|arg| arg == "--skip-unchanged"),
+                merge_json: args.iter().any(|arg| arg == "--merge-json"),
+                merge_toml: args.iter().any(|arg| arg == "--merge-toml"),
+                merge_prefer: parse_merge_prefer(&args),
+                chmod: parse_mode(&args, "--chmod"),
+                umask: parse_mode(&args, "--umask"),
+                owner_map: parse_owner_map(&args),
+                env: flag_values(&args, "--env").pop(),
+                git_add: args.iter().any(|arg| arg == "--git-add"),
+                dirs_only: args.iter().any(|arg| arg == "--dirs-only"),
+                check: args.iter().any(|arg| arg == "--check"),
+                keep_going: args.iter().any(|arg| arg == "--keep-going"),
+                conflict_report: flag_values(&args, "--conflict-report").pop().map(|file| normalize_path(Path::new(&file))),
+                overwrite_confirmed: overwrite,
+                update_lockfile: !args.iter().any(|arg| arg == "--no-lock"),
+                no_progress: args.iter().any(|arg| arg == "--no-progress"),
+                timings: args.iter().any(|arg| arg == "--timings"),
+                quiet: args.iter().any(|arg| arg == "--quiet"),
+                json: args.iter().any(|arg| arg == "--json"),
+            };
+            if !options.quiet && !options.json {
+                check_for_update_normalize();
+            }
+            if args.iter().any(|arg| arg == "--list-only") {
+                let Some(template_name) = args.get(2).filter(|arg| !arg.starts_with("--")) else {
+                    println!("--list-only needs a template name.");
+                    std::process::exit(1);
+                };
+                let templates_dir = options.templates_dir.clone().unwrap_or_else(get_templates_dir);
+                let _lock = lock_store_or_exit(&templates_dir, false);
+                if !show_template_tree(template_name, &templates_dir, OutputFormat::Tree) {
+                    std::process::exit(1);
+                }
+                return;
+            }
+            install_interrupt_handler();
+            let _lock = lock_store_or_exit(&options.templates_dir.clone().unwrap_or_else(get_templates_dir), false);
+            run_with_jobs(parse_jobs(&args), || match args.get(2).filter(|arg| !arg.starts_with("--")) {
+                Some(template_name) => {
+                    if let Err(err) = apply_template(template_name, &options) {
+                        report_template_error(template_name, &err);
+                        std::process::exit(1);
+                    }
+                }
+                None => apply_from_lockfile(&options),
+            })
+        }
+        "tree" if args.len() >= 3 => {
+            let templates_dir = flag_values(&args, "--dir").pop()
+                .map(|dir| normalize_path(Path::new(&dir)))
+                .unwrap_or_else(get_templates_dir);
+            let _lock = lock_store_or_exit(&templates_dir, false);
+            if !show_template_tree(&args[2], &templates_dir, parse_output_format(&args, OutputFormat::Tree)) {
+                std::process::exit(1);
+            }
+        }
+        "verify" if args.len() >= 3 => {
+            let templates_dir = flag_values(&args, "--dir").pop()
+                .map(|dir| normalize_path(Path::new(&dir)))
+                .unwrap_or_else(get_templates_dir);
+            let _lock = lock_store_or_exit(&templates_dir, false);
+            if !verify_template(&args[2], &templates_dir, args.iter().any(|arg| arg == "--deep"),
+                flag_values(&args, "--password").pop().as_deref(), !args.iter().any(|arg| arg == "--no-keyring")) {
+                std::process::exit(1);
+            }
+        }
+        "path" if args.len() >= 3 => {
+            let templates_dir = flag_values(&args, "--dir").pop()
+                .map(|dir| normalize_path(Path::new(&dir)))
+                .unwrap_or_else(resolve_templates_dir);
+            let _lock = lock_store_or_exit(&templates_dir, false);
+            if !show_template_path(&args[2], &templates_dir) {
+                std::process::exit(1);
+            }
+        }
+        "repair" if args.len() == 3 => {
+            check_template_name(&args[2]);
+            let _lock = lock_store_or_exit(&get_templates_dir(), true);
+            repair_template(&args[2])
+        }
+        "migrate" if args.len() >= 3 => {
+            let backup = args.iter().any(|arg| arg == "--backup");
+            let templates_dir = get_templates_dir();
+            let names: Vec<String> = if args.iter().any(|arg| arg == "--all") {
+                template_names(&templates_dir)
+            } else {
+                args[2..].iter().filter(|arg| !arg.starts_with("--")).cloned().collect()
+            };
+            if names.is_empty() && !args.iter().any(|arg| arg == "--all") {
+                print_usage();
+                return;
+            }
+            for name in &names {
+                check_template_name(name);
+            }
+            let _lock = lock_store_or_exit(&templates_dir, true);
+            migrate_templates(&names, backup)
+        }
+        "touch" if args.len() == 3 => {
+            check_template_name(&args[2]);
+            let _lock = lock_store_or_exit(&get_templates_dir(), true);
+            touch_template(&args[2])
+        }
+        "mv-in" if args.len() == 5 => {
+            check_template_name(&args[2]);
+            let _lock = lock_store_or_exit(&get_templates_dir(), true);
+            move_in_template(&args[2], &args[3], &args[4])
         }
         "delete" if args.len() == 3 => {
+            check_template_name(&args[2]);
             check_for_update_normalize();
+            let _lock = lock_store_or_exit(&get_templates_dir(), true);
             delete_template(&args[2])
         }
+        "serve" => serve(),
+        "stats" => {
+            let _lock = lock_store_or_exit(&get_templates_dir(), false);
+            show_store_stats(args.iter().any(|arg| arg == "--count-files"), args.iter().any(|arg| arg == "--json"))
+        }
         "list" => {
-            list_templates()
+            let dir = flag_values(&args, "--dir").pop().map(|dir| normalize_path(Path::new(&dir)));
+            let _lock = lock_store_or_exit(dir.as_deref().unwrap_or(&resolve_templates_dir()), false);
+            list_templates(dir.as_deref(), parse_output_format(&args, OutputFormat::Flat),
+                args.iter().any(|arg| arg == "--verbose"), args.iter().any(|arg| arg == "--stale"))
         }
-        "export" if args.len() == 4 => {
-            check_for_update_normalize();
-            export_template(&args[2], Path::new(&args[3]))
+        "export" => {
+            let with_checksum = args.iter().any(|arg| arg == "--with-checksum");
+            let export_args: Vec<String> = args[2..].iter().filter(|arg| *arg != "--with-checksum").cloned().collect();
+            let _lock = lock_store_or_exit(&get_templates_dir(), false);
+            let exported: Vec<PathBuf> = match export_args.as_slice() {
+                [all, output_dir] if all == "--all" => {
+                    check_for_update_normalize();
+                    let archive_path = normalize_path(Path::new(output_dir)).join("tdmcli-templates.zip");
+                    export_templates_archive(&template_names(&get_templates_dir()), &archive_path).into_iter().collect()
+                }
+                [names @ .., archive] if !names.is_empty() && archive.ends_with(".zip") => {
+                    check_for_update_normalize();
+                    export_templates_archive(&expand_template_patterns(names), &normalize_path(Path::new(archive)))
+                        .into_iter().collect()
+                }
+                [template_name, output_dir] => {
+                    check_for_update_normalize();
+                    let output_dir = normalize_path(Path::new(output_dir));
+                    expand_template_patterns(std::slice::from_ref(template_name)).iter()
+                        .filter_map(|name| export_template(name, &output_dir))
+                        .collect()
+                }
+                _ => {
+                    print_usage();
+                    return;
+                }
+            };
+            if with_checksum {
+                for exported in &exported {
+                    write_checksum_file(exported);
+                }
+            }
         }
         "import" if args.len() >= 3 => {
             check_for_update_normalize();
-            import_template(Path::new(&args[2]), args.get(3).map(String::as_str))
+            let template_name = args.iter().skip(3).find(|arg| !arg.starts_with('-')).map(String::as_str);
+            let _lock = lock_store_or_exit(&get_templates_dir(), true);
+            import_template(&normalize_path(Path::new(&args[2])), template_name, args.iter().any(|arg| arg == "--move"))
+        }
+        "validate-ignore" => {
+            let ignore_file = flag_values(&args, "--ignore-file").pop().map(|file| normalize_path(Path::new(&file)));
+            validate_ignore(
+                &env::current_dir().unwrap(),
+                ignore_file.as_deref(),
+                args.iter().any(|arg| arg == "--hiddenfolder"),
+                !args.iter().any(|arg| arg == "--include-vcs"),
+                resolve_ignore_case(&args, None),
+            )
+        }
+        "register" => {
+            register_file_association()
+        }
+        "unregister" => {
+            unregister_file_association()
         }
         "show-dir" => {
             show_template_directory();
         }
+        "change-dir" if args.len() == 3 && (args[2] == "-" || args[2] == "--reset") => reset_template_dir(),
         "change-dir" if args.len() == 3 => {
-            let new_dir = Path::new(&args[2]);
-            change_template_dir(new_dir);
+            let new_dir = normalize_path(Path::new(&args[2]));
+            change_template_dir(&new_dir);
         }
         "version" | "-v" => {
             show_version()
@@ -488,19 +6002,457 @@ Examples:
         "-u" => {
             check_for_updates()
         }
-        _ => println!(r#"Usage: tdmcli <command> [arguments]
+        _ => print_usage(),
+    }
+}
 
-Examples:
-  tdmcli create <template_name> [--hiddenfolder] [--excludeignore]   Create a template (include hidden folders if flag provided, exclude .tdmignore if flag provided).
-  tdmcli get <template_name>       Apply the template.
-  tdmcli delete <template_name>    Delete a template.
-  tdmcli list                      Show all templates.
-  tdmcli import <input_file> [template_name]      Import an external template.
-  tdmcli export <template_name> <output_dir>        Export template.
-  tdmcli show-dir                  Show the directory where templates are stored.
-  tdmcli change-dir <new_directory>   Change template directory.
-  tdmcli -v                        Show the current version.
-  tdmcli -u                        Check for updates.
-  tdmcli help                      Show this help."#),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The cheapest key derivation, since the tests only check the format.
+    fn test_cipher(passphrase: &str) -> TemplateCipher {
+        TemplateCipher::derive(passphrase, b"tdmcli-testing".to_vec(), argon2::Params::new(8, 1, 1, Some(32)).unwrap()).unwrap()
+    }
+
+    fn template_files(template: &Template) -> Vec<&TemplateFile> {
+        template.entries.iter()
+            .filter_map(|entry| match entry {
+                TemplateEntry::File(file) => Some(&**file),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn write_and_parse(template: &Template) -> Template {
+        let mut written = Vec::new();
+        write_template(&mut written, template).unwrap();
+        parse_template_bytes(&written).unwrap()
+    }
+
+    #[test]
+    fn decode_body_matches_xor_crypt_across_chunks() {
+        assert_ne!(DECODE_CHUNK_SIZE % KEY.len(), 0, "chunks should not line up with the key");
+        for len in [0, 1, DECODE_CHUNK_SIZE - 1, DECODE_CHUNK_SIZE, DECODE_CHUNK_SIZE + 1, 3 * DECODE_CHUNK_SIZE + 7] {
+            let content: Vec<u8> = (0..len).map(|i| (i * 31 % 251) as u8).collect();
+            let obfuscated = xor_crypt(&content, KEY);
+            for encoding in [Encoding::Base64, Encoding::Base64Url, Encoding::Hex] {
+                let file = TemplateFile { body: encoding.encode(&obfuscated), ..TemplateFile::default() };
+                let codec = BodyCodec { encoding, compressed: false, cipher: None };
+                let mut decoded = Vec::new();
+                let hash = decode_body(&file, codec, &mut decoded).unwrap();
+                assert!(decoded == xor_crypt(&obfuscated, KEY), "{} bytes of {} differ", len, encoding.name());
+                assert_eq!(hash, sha256_hex(&content));
+            }
+        }
+    }
+
+    fn parse_error(template: &str) -> TemplateError {
+        match parse_template(template) {
+            Ok(_) => panic!("the malformed template was accepted"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn truncated_template_names_the_file_entry() {
+        let err = parse_error("TDMCLI_FORMAT: 2\nDIR: src\nEND_OF_DIR\nFILE: src/a.txt\nSIZE: 4\n");
+        assert!(matches!(&err, TemplateError::UnexpectedEof { path, line: 4 } if path == "src/a.txt"), "{}", err);
+        let err = parse_error("TDMCLI_FORMAT: 2\nFILE: a.txt\nSIZE: 4\nYWJj\n");
+        assert!(matches!(&err, TemplateError::UnexpectedEof { path, line: 2 } if path == "a.txt"), "{}", err);
+        let err = parse_error("FILE: a.txt\nSIZE: 4\n");
+        assert!(matches!(&err, TemplateError::UnexpectedEof { path, line: 1 } if path == "a.txt"), "{}", err);
+    }
+
+    #[test]
+    fn bad_size_is_reported_with_its_line() {
+        let err = parse_error("TDMCLI_FORMAT: 2\nFILE: a.txt\nSIZE: four\nYWJj\nEND_OF_FILE\n");
+        assert!(matches!(&err, TemplateError::InvalidSize { path, line: 3, text } if path == "a.txt" && text == "SIZE: four"), "{}", err);
+        assert_eq!(err.to_string(), "malformed SIZE for file a.txt on line 3: 'SIZE: four'");
+        let err = parse_error("FILE: a.txt\nSIZE\nYWJj\nEND_OF_FILE\n");
+        assert!(matches!(&err, TemplateError::InvalidSize { line: 2, .. }), "{}", err);
+        let err = parse_error("TDMCLI_FORMAT: 2\nFILE: a.txt\nSIZE: 4\nTYPE: folder\nYWJj\nEND_OF_FILE\n");
+        assert!(matches!(&err, TemplateError::MalformedMetadata { line: 4, key, .. } if key == "TYPE"), "{}", err);
+    }
+
+    #[test]
+    fn bad_base64_is_reported_with_its_line() {
+        let err = parse_error("TDMCLI_FORMAT: 2\nFILE: a.txt\nSIZE: 4\nYW%j\nEND_OF_FILE\n");
+        assert!(matches!(&err, TemplateError::BadContent { path, line: 4, encoding: "base64" } if path == "a.txt"), "{}", err);
+        let err = parse_error("TDMCLI_FORMAT: 2\nENCODING: hex\nFILE: a.txt\nSIZE: 3\nabc\nEND_OF_FILE\n");
+        assert!(matches!(&err, TemplateError::BadContent { line: 5, encoding: "hex", .. }), "{}", err);
+    }
+
+    #[test]
+    fn missing_end_of_file_is_reported_with_its_line() {
+        let err = parse_error("TDMCLI_FORMAT: 2\nFILE: a.txt\nSIZE: 4\nYWJj\nFILE: b.txt\nSIZE: 0\n\nEND_OF_FILE\n");
+        assert!(matches!(&err, TemplateError::MissingEndOfFile { path, line: 5 } if path == "a.txt"), "{}", err);
+        let err = parse_error("TDMCLI_FORMAT: 2\nDIR: src\nFILE: a.txt\n");
+        assert!(matches!(&err, TemplateError::MissingEndOfDir { path, line: 2 } if path == "src"), "{}", err);
+    }
+
+    #[test]
+    fn damaged_compressed_content_fails_only_when_decoded() {
+        let template = parse_template("TDMCLI_FORMAT: 2\nCOMPRESSION: gzip\nFILE: a.txt\nSIZE: 4\nLENGTH: 3\nYWJj\nEND_OF_FILE\n").unwrap();
+        let files = template_files(&template);
+        assert_eq!(files[0].line, 3);
+        assert_eq!(files[0].content_len(template.codec()), Some(3));
+        let err = decode_body(files[0], template.codec(), &mut std::io::sink()).unwrap_err();
+        assert!(matches!(err.kind(), std::io::ErrorKind::InvalidData | std::io::ErrorKind::UnexpectedEof), "{}", err);
+    }
+
+    #[test]
+    fn invalid_utf8_is_reported_with_its_line() {
+        let err = parse_template_bytes(b"TDMCLI_FORMAT: 2\nFILE: a\xff.txt\n").err().unwrap();
+        assert!(matches!(err, TemplateError::NotUtf8 { line: 2 }), "{}", err);
+    }
+
+    #[test]
+    fn encrypted_template_round_trips_many_files() {
+        let cipher = test_cipher("secret");
+        let mut header = cipher.header();
+        header.push(("COMPRESSION".to_string(), COMPRESSION.to_string()));
+        let contents: Vec<Vec<u8>> = (0..300).map(|i| format!("line {} of the file\n", i).repeat(i).into_bytes()).collect();
+        let entries = contents.iter().enumerate()
+            .map(|(i, content)| {
+                let (nonce, ciphertext) = cipher.encrypt(&gzip(content).unwrap());
+                let body = Encoding::Base64.encode(&ciphertext);
+                TemplateEntry::File(Box::new(TemplateFile {
+                    path: format!("dir{}/file{}.txt", i % 7, i),
+                    size: Some(body.len()),
+                    length: Some(content.len() as u64),
+                    nonce: Some(nonce),
+                    body,
+                    ..TemplateFile::default()
+                }))
+            })
+            .collect();
+        let parsed = write_and_parse(&Template { format_version: FORMAT_VERSION, header, entries });
+
+        assert_eq!(unlock_template("t", &parsed, Some("wrong"), false).err().as_deref(), Some("wrong passphrase"));
+        let unlocked = unlock_template("t", &parsed, Some("secret"), false).unwrap();
+        let codec = BodyCodec { cipher: unlocked.as_ref(), ..parsed.codec() };
+        let files = template_files(&parsed);
+        assert_eq!(files.len(), contents.len());
+        assert!(files.iter().all(|file| file.hash.is_none() && file.content_len(codec).is_some()));
+        let decoded: Vec<Vec<u8>> = files.par_iter()
+            .map(|file| {
+                let mut content = Vec::new();
+                decode_body(file, codec, &mut content).unwrap();
+                content
+            })
+            .collect();
+        assert_eq!(decoded, contents);
+        for (file, content) in files.iter().zip(&contents) {
+            assert_eq!(file.content_len(codec), Some(content.len() as u64));
+        }
+        assert!(decode_body(files[1], parsed.codec(), &mut std::io::sink()).is_err());
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tdmcli-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn metadata_lines_are_counted_from_the_start_of_the_template() {
+        let err = parse_error("TDMCLI_FORMAT: 2\nFILE: a.txt\nSIZE: 0\n\nEND_OF_FILE\nDIR: src\nEND_OF_DIR\nFILE: src/b.txt\nSIZE: 0\nOWNER: root\n\nEND_OF_FILE\n");
+        assert!(matches!(&err, TemplateError::MalformedMetadata { path, line: 10, key, text }
+            if path == "src/b.txt" && key == "OWNER" && text == "OWNER: root"), "{}", err);
+        let err = parse_error("TDMCLI_FORMAT: 2\nFILE: a.txt\nSIZE: 0\n\nEND_OF_FILE\nFILE: b.txt\nSIZE: -1\n\nEND_OF_FILE\n");
+        assert!(matches!(&err, TemplateError::InvalidSize { path, line: 7, .. } if path == "b.txt"), "{}", err);
+    }
+
+    #[test]
+    fn every_encoding_round_trips() {
+        let content: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        for encoding in [Encoding::Base64, Encoding::Base64Url, Encoding::Hex] {
+            for len in [0, 1, 2, 3, 999, 1000] {
+                let body = encoding.encode(&content[..len]);
+                assert!(encoding.is_valid(&body), "{} of {} bytes", encoding.name(), len);
+                assert_eq!(encoding.decoded_len(&body), len as u64);
+                let mut decoded = Vec::new();
+                encoding.reader(&body).read_to_end(&mut decoded).unwrap();
+                assert_eq!(decoded, &content[..len]);
+            }
+            assert!(Encoding::parse(encoding.name()) == Some(encoding));
+
+            let body = encoding.encode(&xor_crypt(&content, KEY));
+            let header = vec![("ENCODING".to_string(), encoding.name().to_string())];
+            let file = TemplateFile { path: "a.bin".to_string(), size: Some(body.len()), body, ..TemplateFile::default() };
+            let parsed = write_and_parse(&Template { format_version: FORMAT_VERSION, header, entries: vec![TemplateEntry::File(Box::new(file))] });
+            assert!(parsed.encoding() == encoding);
+            let mut decoded = Vec::new();
+            decode_body(template_files(&parsed)[0], parsed.codec(), &mut decoded).unwrap();
+            assert_eq!(decoded, content);
+        }
+        let err = parse_error("TDMCLI_FORMAT: 2\nENCODING: base32\n");
+        assert!(matches!(&err, TemplateError::UnsupportedHeader { line: 2, key, value } if key == "ENCODING" && value == "base32"), "{}", err);
+    }
+
+    #[test]
+    fn delete_entries_round_trip() {
+        let entries = vec![
+            TemplateEntry::Delete("old.txt".to_string()),
+            TemplateEntry::Dir("src".to_string()),
+            TemplateEntry::Delete("src/old.rs".to_string()),
+        ];
+        let parsed = write_and_parse(&Template { format_version: FORMAT_VERSION, header: Vec::new(), entries });
+        let deleted: Vec<&str> = parsed.entries.iter()
+            .filter_map(|entry| match entry {
+                TemplateEntry::Delete(path) => Some(path.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(deleted, ["old.txt", "src/old.rs"]);
+
+        let legacy = write_and_parse(&Template { format_version: 1, header: Vec::new(), entries: parsed.entries });
+        assert!(legacy.entries.iter().all(|entry| !matches!(entry, TemplateEntry::Delete(_))));
+        let err = parse_error("TDMCLI_FORMAT: 2\nDELETE: ../outside.txt\n");
+        assert!(matches!(&err, TemplateError::UnsafePath { kind: "DELETE", line: 2, .. }), "{}", err);
+    }
+
+    #[test]
+    fn gitattributes_classify_text_and_binary() {
+        let dir = test_dir("gitattributes");
+        fs::write(dir.join(".gitattributes"), "# comment\n*.txt text\n*.png binary\n*.dat -text\ndocs/** text eol=lf\ndocs/*.bin binary\n*.md text=auto\n[attr]custom text\n*.rs diff\n").unwrap();
+        let attributes = load_text_attributes(&dir);
+        assert_eq!(attributes.classify(Path::new("notes.txt")), Some(true));
+        assert_eq!(attributes.classify(Path::new("logo.png")), Some(false));
+        assert_eq!(attributes.classify(Path::new("blob.dat")), Some(false));
+        assert_eq!(attributes.classify(Path::new("docs/guide.html")), Some(true));
+        assert_eq!(attributes.classify(Path::new("docs/image.bin")), Some(false), "the last matching line wins");
+        assert_eq!(attributes.classify(Path::new("README.md")), None);
+        assert_eq!(attributes.classify(Path::new("main.rs")), None);
+        assert_eq!(text_attribute("!text"), Some(None));
+        assert_eq!(text_attribute("eol=lf"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn relative_template_dir_is_resolved_against_the_config_dir() {
+        let config_dir = std::env::temp_dir().join("tdmcli-config");
+        assert_eq!(resolve_config_template_dir("templates", &config_dir), config_dir.join("templates"));
+        assert_eq!(resolve_config_template_dir("./a/../templates", &config_dir), config_dir.join("templates"));
+        assert_eq!(resolve_config_template_dir("../shared", &config_dir), std::env::temp_dir().join("shared"));
+        let absolute = std::env::temp_dir().join("elsewhere");
+        assert_eq!(resolve_config_template_dir(absolute.to_str().unwrap(), &config_dir), absolute);
+        if let Some(home) = dirs::home_dir() {
+            assert_eq!(resolve_config_template_dir("~/templates", &config_dir), normalize_path(&home.join("templates")));
+        }
+    }
+
+    #[test]
+    fn tilde_expands_to_the_home_directory() {
+        let Some(home) = dirs::home_dir() else {
+            return;
+        };
+        assert_eq!(expand_tilde(Path::new("~")), home);
+        assert_eq!(expand_tilde(Path::new("~/export/a.tdmcli")), home.join("export/a.tdmcli"));
+        assert_eq!(expand_tilde(Path::new("~other/a")), Path::new("~other/a"));
+        assert_eq!(expand_tilde(Path::new("dir/~/a")), Path::new("dir/~/a"));
+        assert_eq!(normalize_path(Path::new("~/a/../b")), normalize_path(&home.join("b")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn create_skips_symlinks_and_the_nested_templates_dir() {
+        let dir = test_dir("symlinks");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("store")).unwrap();
+        fs::write(dir.join("src/main.rs"), "fn main() {}\n").unwrap();
+        fs::write(dir.join("store/other.tdmcli"), "TDMCLI_FORMAT: 2\n").unwrap();
+        std::os::unix::fs::symlink(dir.join("src/main.rs"), dir.join("link.rs")).unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("src/loop")).unwrap();
+        std::os::unix::fs::symlink(dir.join("src"), dir.join("linked_dir")).unwrap();
+
+        let options = CreateOptions { exclude_symlinks: true, format_version: FORMAT_VERSION, ..CreateOptions::default() };
+        let store_relative = store_in_source(&dir.join("store"), &dir).unwrap();
+        assert_eq!(store_relative.as_deref(), Some(Path::new("store")));
+        let in_store = |path: &Path| path.strip_prefix(&dir).is_ok_and(|relative| relative.starts_with(store_relative.as_deref().unwrap()));
+        let plan = plan_create(&dir, &options, &in_store, false);
+        assert_eq!(plan.files, [dir.join("src/main.rs")]);
+        assert!(plan.empty_dirs.is_empty(), "{:?}", plan.empty_dirs);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn templates_dir_guard() {
+        let store = std::env::temp_dir().join("tdmcli/templates");
+        assert!(store_in_source(&store, &store).is_err());
+        assert!(store_in_source(&store, &store.join("project")).is_err());
+        assert_eq!(store_in_source(&store, &std::env::temp_dir()).unwrap().as_deref(), Some(Path::new("tdmcli/templates")));
+        assert_eq!(store_in_source(&store, &std::env::temp_dir().join("tdmcli-project")).unwrap(), None);
+    }
+
+    fn file_entry(path: &str, env: Option<&str>) -> TemplateEntry {
+        TemplateEntry::File(Box::new(TemplateFile { path: path.to_string(), env: env.map(String::from), ..TemplateFile::default() }))
+    }
+
+    #[test]
+    fn duplicate_file_paths_are_reported_once_in_order() {
+        let entries = vec![
+            file_entry("b.txt", None),
+            file_entry("a.txt", None),
+            TemplateEntry::Dir("a.txt".to_string()),
+            file_entry("a.txt", None),
+            file_entry("b.txt", None),
+            file_entry("a.txt", None),
+            file_entry("config.toml", Some("prod")),
+            file_entry("config.toml", None),
+            file_entry("config.toml", Some("dev")),
+        ];
+        assert_eq!(duplicate_file_paths(&entries), ["a.txt", "b.txt"]);
+        assert!(duplicate_file_paths(&entries[6..]).is_empty());
+        assert_eq!(duplicate_file_paths(&[file_entry("c", Some("prod")), file_entry("c", Some("prod"))]), ["c"]);
+    }
+
+    #[test]
+    fn template_names_must_be_safe_file_names() {
+        for name in ["web app", "rust-cli_2.0", "café", "a.b"] {
+            assert_eq!(validate_template_name(name), Ok(()), "{}", name);
+        }
+        let long = "a".repeat(201);
+        for name in ["", ".hidden", "..", "trailing.", "trailing ", "a/b", r"a\b", "c:d", "a*", "what?", "\"q\"", "<a>", "a|b", "tab\there", long.as_str()] {
+            assert!(validate_template_name(name).is_err(), "{:?} was accepted", name);
+        }
+        assert_eq!(validate_template_name(&"a".repeat(200)), Ok(()));
+    }
+
+    #[test]
+    fn byte_paths_round_trip() {
+        for bytes in [&b"plain/path.txt"[..], b"caf\xc3\xa9.txt", b"line\nbreak", b"100%", b"bad\xff\xfe", b"\x7f"] {
+            let encoded = encode_path_bytes(bytes);
+            assert!(encoded.bytes().all(|byte| !must_escape_path_byte(byte) || byte == b'%'), "{}", encoded);
+            assert_eq!(decode_path_bytes(&encoded).as_deref(), Some(bytes));
+        }
+        assert_eq!(encode_path_bytes("café.txt".as_bytes()), "caf%C3%A9.txt");
+        assert_eq!(decode_path_bytes("caf%c3%a9.txt").as_deref(), Some("café.txt".as_bytes()));
+        for encoded in ["%2F", "%2E%2E", "%41", "%00", "%4", "%zz", "caf\u{e9}", "a\tb"] {
+            assert_eq!(decode_path_bytes(encoded), None, "{:?} was accepted", encoded);
+        }
+        assert!(matches!(parse_entry_path("FILE", "%2F/a", 3, true), Err(TemplateError::BadPercentPath { line: 3, .. })));
+        assert!(parse_entry_path("FILE", "%2F/a", 3, false).is_ok());
+        #[cfg(unix)]
+        assert_eq!(path_from_bytes(decode_path_bytes(&path_text(&path_from_bytes(b"x\xff".to_vec()), true)).unwrap()), path_from_bytes(b"x\xff".to_vec()));
+    }
+
+    #[test]
+    fn paths_are_normalized_to_absolute_paths() {
+        let current_dir = std::env::current_dir().unwrap();
+        assert_eq!(normalize_path(Path::new("a/./b/../c")), current_dir.join("a/c"));
+        assert_eq!(normalize_path(Path::new(".")), current_dir);
+        let root = std::env::temp_dir();
+        assert_eq!(normalize_path(&root.join("x/../y/./z/")), root.join("y/z"));
+        assert!(normalize_path(Path::new("../..")).is_absolute());
+    }
+
+    #[test]
+    fn ignore_lines_handle_comments_and_escapes() {
+        assert_eq!(parse_ignore_line("  target/  ", false).as_deref(), Some("target/"));
+        assert_eq!(parse_ignore_line("# comment", false), None);
+        assert_eq!(parse_ignore_line("   ", false), None);
+        assert_eq!(parse_ignore_line(r"\#notes.md", false).as_deref(), Some("#notes.md"));
+        assert_eq!(parse_ignore_line(r"\!important", false).as_deref(), Some("!important"));
+        assert_eq!(parse_ignore_line(r"\other", false).as_deref(), Some(r"\other"));
+        assert_eq!(parse_ignore_line("build/ # output", false).as_deref(), Some("build/ # output"));
+        assert_eq!(parse_ignore_line("build/ # output", true).as_deref(), Some("build/"));
+        assert_eq!(parse_ignore_line("a#b", true).as_deref(), Some("a#b"));
+    }
+
+    #[test]
+    fn tdmignore_matches_with_the_requested_case() {
+        let dir = test_dir("tdmignore");
+        fs::write(dir.join(".tdmignore"), format!("{}\nREADME.md\n\\#draft.txt\nbuild/ # output\nunused\n", INLINE_COMMENTS_DIRECTIVE)).unwrap();
+        for ignore_case in [false, true] {
+            let patterns = load_ignore_patterns(&dir, &[], ignore_case);
+            let ignored = |path: &str| should_ignore(&dir.join(path), &dir, &patterns, false);
+            assert!(ignored("README.md"));
+            assert_eq!(ignored("readme.MD"), ignore_case);
+            assert!(ignored("docs/README.md"));
+            assert!(ignored("#draft.txt"));
+            assert!(ignored("build/out.o"));
+            assert!(!ignored("src/main.rs"));
+            assert!(!ignored(".tdmignore"));
+            assert!(should_ignore(&dir.join(".tdmignore"), &dir, &patterns, true));
+            let rules: Vec<(usize, &str)> = patterns.rules.iter().map(|(line, rule)| (*line, rule.as_str())).collect();
+            assert_eq!(rules, [(2, "README.md"), (3, r"\#draft.txt"), (4, "build/ # output"), (5, "unused")]);
+            assert_eq!(patterns.hits[3].load(Ordering::Relaxed), 0);
+        }
+        fs::remove_dir_all(&dir).unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn failed_replacement_leaves_no_temp_file() {
+        let dir = test_dir("replace");
+        let template_path = dir.join("t.tdmcli");
+        let mut template = Template { format_version: FORMAT_VERSION, header: Vec::new(), entries: vec![TemplateEntry::Dir("src".to_string())] };
+        replace_template_file(&template_path, &mut template).unwrap();
+        assert_eq!(parse_template_bytes(&fs::read(&template_path).unwrap()).unwrap().entries.len(), 1);
+
+        let blocked = dir.join("blocked.tdmcli");
+        fs::create_dir_all(blocked.join("occupied")).unwrap();
+        assert!(replace_template_file(&blocked, &mut template).is_err());
+        assert!(!with_extension_suffix(&blocked, "tmp").exists());
+        assert!(replace_template_file(&dir.join("missing/t.tdmcli"), &mut template).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recreating_a_template_twice_in_one_process_does_not_wait_for_its_own_lock() {
+        let dir = test_dir("recreate");
+        let (source, store) = (dir.join("project"), dir.join("templates"));
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&store).unwrap();
+        let options = CreateOptions { format_version: FORMAT_VERSION, compress: true, quiet: true, ..CreateOptions::default() };
+        let started = Instant::now();
+        for content in ["first\n", "second\n"] {
+            fs::write(source.join("a.txt"), content).unwrap();
+            assert!(create_template_in(&store, "watched", &source, &options));
+            let template = parse_template_bytes(&fs::read(store.join(template_file_name("watched"))).unwrap()).unwrap();
+            let mut decoded = Vec::new();
+            decode_body(template_files(&template)[0], template.codec(), &mut decoded).unwrap();
+            assert_eq!(decoded, content.as_bytes());
+        }
+        assert!(started.elapsed() < Duration::from_secs(DEFAULT_LOCK_TIMEOUT_SECS));
+        assert!(lock_store(&store, true).unwrap().is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn expanded_paths_stay_inside_the_destination() {
+        for path in ["config/app.toml", "./a", "a/b.c..d"] {
+            assert!(is_safe_expanded_path(Path::new(path), false), "{}", path);
+        }
+        for path in ["", "../../etc/passwd", "a/../../b", "a/..", "/etc/passwd"] {
+            assert!(!is_safe_expanded_path(Path::new(path), false), "{:?} was accepted", path);
+        }
+        let absolute = std::env::temp_dir().join("app/config");
+        assert!(is_safe_expanded_path(&absolute, true));
+        assert!(!is_safe_expanded_path(&absolute.join("../../x"), true));
+        assert!(!is_safe_expanded_path(Path::new("../x"), true));
+
+        let mut undefined = Vec::new();
+        assert_eq!(expand_env("$TDMCLI_TEST_UNDEFINED/a", &mut undefined), "$TDMCLI_TEST_UNDEFINED/a");
+        assert_eq!(undefined, ["TDMCLI_TEST_UNDEFINED"]);
+    }
+
+    #[test]
+    fn git_paths_are_split_on_nul_without_unquoting() {
+        assert_eq!(split_git_paths("café.txt\0new \"q\".txt\0line\nbreak\0".as_bytes()),
+            [PathBuf::from("café.txt"), PathBuf::from("new \"q\".txt"), PathBuf::from("line\nbreak")]);
+        assert!(split_git_paths(b"").is_empty());
+    }
+
+    #[test]
+    fn serve_apply_only_overwrites_when_asked() {
+        let args = |request: serde_json::Value| serve_conflict_args(&request).map_err(|err| err.code);
+        assert_eq!(args(serde_json::json!({ "cmd": "apply", "name": "x", "overwrite": true })), Ok(&["--overwrite"][..]));
+        assert_eq!(args(serde_json::json!({ "cmd": "apply", "name": "x", "overwrite": false })), Ok(&["--skip-existing"][..]));
+        assert_eq!(args(serde_json::json!({ "cmd": "apply", "name": "x" })), Ok(&["--skip-existing"][..]));
+        assert_eq!(args(serde_json::json!({ "cmd": "apply", "name": "x", "overwrite": "yes" })), Err("invalid_params"));
+        assert_eq!(args(serde_json::json!({ "cmd": "apply", "name": "x", "args": ["--overwrite"] })), Ok(&[][..]));
+    }
+}